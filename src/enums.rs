@@ -23,12 +23,154 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
+use super::glob_matches;
+use super::structs::{ApplyConfig, Config, PinConfig, PurgeConfig, SnapshotConfig};
+use regex::Regex;
+use std::fmt;
+
 #[derive(Debug)]
 pub enum SystemError {
     SpawnProcess(String),
     ReadingFromString(String),
     OpeningFile(String),
     DeleteSnapshots(String),
+    RenameSnapshot(String),
+    ChannelProgram(String),
+    CreateSnapshots(String),
+    WritingToFile(String),
+    SetProperty(String),
 }
 
 pub type SystemResult = Result<String, SystemError>;
+
+/// Where a snapshot's timestamp is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimestampSource {
+    Name,
+    Creation,
+}
+
+impl fmt::Display for TimestampSource {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimestampSource::Name => write!(f, "name"),
+            TimestampSource::Creation => write!(f, "creation"),
+        }
+    }
+}
+
+/// What the age of a snapshot is measured against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CutoffAnchor {
+    Now,
+    Dataset,
+    Pool,
+}
+
+impl fmt::Display for CutoffAnchor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CutoffAnchor::Now => write!(f, "now"),
+            CutoffAnchor::Dataset => write!(f, "dataset"),
+            CutoffAnchor::Pool => write!(f, "pool"),
+        }
+    }
+}
+
+/// The order the snapshots are destroyed in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeletionOrder {
+    Dataset,
+    Oldest,
+}
+
+impl fmt::Display for DeletionOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DeletionOrder::Dataset => write!(f, "dataset"),
+            DeletionOrder::Oldest => write!(f, "oldest"),
+        }
+    }
+}
+
+/// How the stale snapshots are destroyed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Batched,
+    ChannelProgram,
+}
+
+impl fmt::Display for Backend {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Backend::Batched => write!(f, "batched"),
+            Backend::ChannelProgram => write!(f, "channel-program"),
+        }
+    }
+}
+
+/// The action requested on the command line.
+#[derive(Debug)]
+pub enum Action {
+    Clean(Config),
+    Snapshot(SnapshotConfig),
+    Rotate(SnapshotConfig, Config),
+    Plan(Config, String),
+    Apply(ApplyConfig),
+    Purge(PurgeConfig),
+    Pin(PinConfig),
+    Unpin(PinConfig),
+    Pins(PinConfig),
+}
+
+/// A pattern that is matched against the full name of a snapshot.
+#[derive(Debug)]
+pub enum Pattern {
+    Exact(String),
+    Glob(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub fn matches(&self, name: &str) -> bool {
+        match self {
+            Pattern::Exact(pattern) => pattern == name,
+            Pattern::Glob(pattern) => glob_matches(pattern, name),
+            Pattern::Regex(regex) => regex.is_match(name),
+        }
+    }
+}
+
+impl fmt::Display for Pattern {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Pattern::Exact(pattern) => write!(f, "{}", pattern),
+            Pattern::Glob(pattern) => write!(f, "{}", pattern),
+            Pattern::Regex(regex) => write!(f, "re:{}", regex),
+        }
+    }
+}
+
+/// A problem with an entry in the exclude file along with its line number.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExcludeProblem {
+    Unparseable(usize, String, String),
+    OtherPool(usize, String),
+    Unmatched(usize, String),
+}
+
+impl fmt::Display for ExcludeProblem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExcludeProblem::Unparseable(line, entry, reason) => {
+                write!(f, "Line {}: {}: {}", line, reason, entry)
+            }
+            ExcludeProblem::OtherPool(line, entry) => {
+                write!(f, "Line {}: Refers to another pool: {}", line, entry)
+            }
+            ExcludeProblem::Unmatched(line, entry) => {
+                write!(f, "Line {}: Doesn't match any snapshot: {}", line, entry)
+            }
+        }
+    }
+}
@@ -30,15 +30,42 @@ pub mod traits;
 
 use chrono::prelude::*;
 use chrono::Duration;
-use clap::{App, Arg};
-use enums::SystemResult;
-use std::collections::HashSet;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use enums::{
+    Action, Backend, CutoffAnchor, DeletionOrder, ExcludeProblem, Pattern, SystemError,
+    SystemResult, TimestampSource,
+};
+use regex::Regex;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::io;
 use std::io::prelude::*;
-use structs::{Config, RealCommunicator, Snapshot};
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Instant;
+use structs::{
+    ApplyConfig, Config, DestroyConfig, Exclusion, Options, Pin, PinConfig, Plan, PurgeConfig,
+    RealCommunicator, Snapshot, SnapshotConfig,
+};
 use traits::Communicator;
 
 const SNAPSHOT_FORMAT: &str = "%Y-%m-%d-%H%M-%S";
+const PIN_DATE_FORMAT: &str = "%Y-%m-%d";
+const DEFAULT_ITERATIONS: u32 = 100;
+const DEFAULT_CREATION_TOLERANCE: i64 = 60;
+const DEFAULT_JOBS: usize = 1;
+const DEFAULT_KEEP_NEWEST: usize = 1;
+const EXIT_CODE_MASS_DELETION: i32 = 3;
+const EXIT_CODE_INCOMPLETE: i32 = 4;
+const DEFAULT_MAX_PLAN_AGE: &str = "24h";
+const DEFAULT_GRACE_PERIOD: &str = "7d";
+const DEFAULT_ARG_MAX: usize = 262_144;
+const LINUX_MAX_ARGUMENT_LENGTH: usize = 131_072;
+const TRASH_MARKER: &str = "-TRASH-";
+const DEFAULT_PROTECT_PROPERTY: &str = "honeydew:protect";
+const DEFAULT_EXPIRES_PROPERTY: &str = "honeydew:expires";
+const CONFIG_FILE_OPTIONS: &[&str] = &["max-age", "include-dataset", "exclude-dataset"];
 
 const APP_NAME: &str = "Honeydew";
 const APP_VERSION: &str = clap::crate_version!();
@@ -46,6 +73,10 @@ const APP_AUTHOR: &str = clap::crate_authors!();
 const APP_DESCRIPTION: &str = clap::crate_description!();
 const APP_LICENSE: &str = "Simplified BSD License";
 
+/// The names after the '@' of every snapshot in each dataset, in the order
+/// they were created.
+type SnapshotOrder = HashMap<String, Vec<String>>;
+
 // Integration Tested Only
 fn print_header() {
     println!("------------------------------");
@@ -58,65 +89,118 @@ fn print_header() {
 // Integration Tested Only
 pub fn run() {
     let communicator = RealCommunicator;
-    let config = parse_arguments(&communicator);
+    let action = parse_arguments(&communicator);
     print_header();
 
-    config.print();
+    match action {
+        Action::Clean(config) => clean(&communicator, &config),
+        Action::Snapshot(config) => {
+            if let Err(e) = take_snapshots(&communicator, &config, Local::now()) {
+                panic!("{:?}", e);
+            }
+        }
+        Action::Rotate(snapshot_config, config) => {
+            if let Err(e) = rotate(&communicator, &snapshot_config, &config, Local::now()) {
+                println!("[Error] Failed to take the snapshots. Nothing will be deleted.");
+                panic!("{:?}", e);
+            }
+        }
+        Action::Plan(config, plan_file) => {
+            match plan(&communicator, &config, &plan_file, Local::now()) {
+                Err(e) => panic!("{:?}", e),
+                Ok(false) => process::exit(EXIT_CODE_MASS_DELETION),
+                Ok(true) => (),
+            }
+        }
+        Action::Apply(config) => apply(&communicator, &config, Local::now()),
+        Action::Purge(config) => purge(&communicator, &config, Local::now()),
+        Action::Pin(config) => {
+            if let Err(e) = pin(&communicator, &config) {
+                panic!("{:?}", e);
+            }
+        }
+        Action::Unpin(config) => {
+            if let Err(e) = unpin(&communicator, &config) {
+                panic!("{:?}", e);
+            }
+        }
+        Action::Pins(config) => list_pins(&communicator, &config),
+    }
+}
 
-    let excluded_snapshots: Vec<Snapshot>;
-    if config.exclude_file().is_empty() {
-        excluded_snapshots = Vec::new();
+// Integration Tested Only
+/// Takes the new snapshots and then cleans the stale ones. Nothing is
+/// cleaned if the snapshots couldn't be taken. In a dry run, or when only a
+/// script is written, the snapshots are listed instead of taken.
+fn rotate<T: Communicator>(
+    communicator: &T,
+    snapshot_config: &SnapshotConfig,
+    config: &Config,
+    time: DateTime<Local>,
+) -> SystemResult {
+    let results = if config.should_dry_run() || !config.emit_script().is_empty() {
+        snapshot_config.print();
+        for snapshot in
+            get_snapshot_names(snapshot_config.datasets(), snapshot_config.label(), time)
+        {
+            println!("Would Create | {}", snapshot);
+        }
+        println!();
+        String::new()
     } else {
-        excluded_snapshots = get_excluded_snapshots(&communicator, &config);
-    }
+        take_snapshots(communicator, snapshot_config, time)?
+    };
+    clean(communicator, config);
+    Ok(results)
+}
 
-    let stale_snapshots = get_relevant_snapshots(&communicator, &config, &excluded_snapshots);
+// Integration Tested Only
+/// Cleans the stale snapshots for the configured pool.
+fn clean<T: Communicator>(communicator: &T, config: &Config) {
+    let (stale_snapshots, mass_deletion) = queue_snapshots(communicator, config);
 
-    if config.should_show_queued() {
-        println!("These snapshots are QUEUED for REMOVAL:");
-        println!("----------------");
-        for snapshot_to_delete in &stale_snapshots {
-            println!("{}", snapshot_to_delete);
+    if !config.emit_script().is_empty() {
+        // Whoever runs the script deletes the snapshots, so it gets the same
+        // guardrail as a normal run.
+        if mass_deletion.is_some()
+            && !config.allow_mass_deletion()
+            && !confirm_mass_deletion(config)
+        {
+            println!("Nothing will be written. Take care!");
+            return;
         }
-        println!("");
-    }
-
-    if config.should_show_excluded() {
-        println!("These snapshots are EXCLUDED from REMOVAL:");
-        println!("----------------");
-        for snapshot_to_exclude in &excluded_snapshots {
-            println!("{}", snapshot_to_exclude);
+        if let Err(e) = emit_script(communicator, config, &stale_snapshots, Local::now()) {
+            panic!("{:?}", e);
         }
-        println!("");
+        return;
     }
 
-    println!("Amount of Snapshots to Remove: {}", stale_snapshots.len());
-    println!(
-        "Amount of Snapshots to Exclude: {}",
-        excluded_snapshots.len()
-    );
-    println!("");
-
     if !config.should_dry_run() {
-        if stale_snapshots.len() == 0 {
+        if stale_snapshots.is_empty() {
             println!("Your pool is already clean. Take care!");
             return;
         }
 
+        if mass_deletion.is_some() && !config.allow_mass_deletion() {
+            if confirm_mass_deletion(config) {
+                remove_snapshots(communicator, config, &stale_snapshots);
+            } else {
+                println!("Nothing will be deleted. Take care!");
+            }
+            return;
+        }
+
         if config.no_confirm() {
-            destroy_snapshots(&communicator, &stale_snapshots, config.iteration_count());
+            remove_snapshots(communicator, config, &stale_snapshots);
             return;
         }
-        print!("Do you want to delete the above snapshots? [y/N]: ");
-        io::stdout().flush().unwrap();
-        let mut input = String::new();
-        match io::stdin().read_line(&mut input) {
-            Ok(_) => (),
-            Err(e) => panic!("Invalid Input. Exiting. Error: {}", e),
+        let input = if config.quarantine() {
+            ask("Do you want to move the above snapshots to the trash? [y/N]: ")
+        } else {
+            ask("Do you want to delete the above snapshots? [y/N]: ")
         };
-        println!("");
         if input.trim().eq_ignore_ascii_case("y") {
-            destroy_snapshots(&communicator, &stale_snapshots, config.iteration_count());
+            remove_snapshots(communicator, config, &stale_snapshots);
         } else {
             println!("Nothing will be deleted. Take care!");
         }
@@ -124,631 +208,4931 @@ pub fn run() {
 }
 
 // Integration Tested Only
-/// Parses the command line arguments and returns the configuration.
-///
-/// This function will panic if you pass it an exclude file that does not exist.
-pub fn parse_arguments<T: Communicator>(communicator: &T) -> Config {
-    const DEFAULT_ITERATIONS: u32 = 100;
+/// Asks for the name of the pool before going over a mass deletion limit.
+/// Exits with EXIT_CODE_MASS_DELETION when there's no one to ask.
+fn confirm_mass_deletion(config: &Config) -> bool {
+    if config.no_confirm() {
+        println!("[Error] Refusing to delete this many snapshots without confirmation. Nothing will be deleted.");
+        process::exit(EXIT_CODE_MASS_DELETION);
+    }
+    let input = ask("Type the name of the pool to delete the above snapshots: ");
+    input.trim() == config.pool()
+}
 
-    let matches = App::new(APP_NAME)
-        .version(APP_VERSION)
-        .author(APP_AUTHOR)
-        .about(APP_DESCRIPTION)
-        .arg(
-            Arg::with_name("pool")
-                .short("p")
-                .long("pool")
-                .help("The pool you want to clean.")
-                .required(true)
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("date")
-                .short("d")
-                .long("date")
-                .help(
-                    "The slice date that you want to use as your end point for snapshot deletions.",
-                )
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("exclude-file")
-                .short("e")
-                .long("exclude-file")
-                .help("Excludes the list of snapshots in this file (one snapshot per line).")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("show-queued")
-                .short("s")
-                .long("show-queued")
-                .help("Show snapshots that will be removed."),
-        )
-        .arg(
-            Arg::with_name("show-excluded")
-                .short("x")
-                .long("show-excluded")
-                .help("Show snapshots that will be excluded."),
-        )
-        .arg(
-            Arg::with_name("dry-run")
-                .short("n")
-                .long("dry-run")
-                .help("Performs a dry run. No deletions will occur."),
-        )
-        .arg(
-            Arg::with_name("per-iteration")
-                .short("i")
-                .long("per-iteration")
-                .help("Number of snapshots to delete per iteration.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("no-confirm")
-                .short("f")
-                .long("no-confirm")
-                .help("Deletes snapshots without confirmation. Used primarily for cron."),
-        )
-        .arg(
-            Arg::with_name("label")
-                .short("l")
-                .long("label")
-                .help("The label of the snapshots that should be cleaned.")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("show-config")
-                .short("c")
-                .long("show-config")
-                .help("Displays the full configuration options used by the application."),
+// Integration Tested Only
+/// Moves the snapshots to the trash when quarantining, and destroys them
+/// otherwise. Exits with EXIT_CODE_INCOMPLETE when the budget of the run
+/// left any of them.
+fn remove_snapshots<T: Communicator>(communicator: &T, config: &Config, snapshots: &[Snapshot]) {
+    let destroy_config = config.destroy_config();
+    let removed_count = if config.quarantine() {
+        trash_snapshots(communicator, snapshots, Local::now(), &destroy_config).len()
+    } else if config.recursive_sets() {
+        destroy_sets(communicator, snapshots, &destroy_config).len()
+    } else if config.backend() == &Backend::ChannelProgram {
+        match destroy_with_channel_program(communicator, config, snapshots) {
+            Ok(deleted_snapshots) => deleted_snapshots.len(),
+            Err(e) => {
+                println!("[Error] The channel program failed.");
+                panic!("{:?}", e);
+            }
+        }
+    } else {
+        destroy_snapshots(
+            communicator,
+            snapshots,
+            get_order_for(communicator, config).as_ref(),
+            &destroy_config,
         )
-        .get_matches();
-
-    let pool = matches.value_of("pool").unwrap();
-    let label = matches.value_of("label").unwrap_or("");
-    let exclude_file = matches.value_of("exclude-file").unwrap_or("");
-    let show_config = matches.is_present("show-config");
-    let date = matches.value_of("date").unwrap_or("");
-    let no_confirm = matches.is_present("no-confirm");
-    let iteration_count: u32 = match matches.value_of("per-iteration") {
-        Some(v) => v.parse().unwrap(),
-        None => DEFAULT_ITERATIONS,
+        .len()
     };
-    let dry_run = matches.is_present("dry-run");
-    let show_queued = matches.is_present("show-queued");
-    let show_excluded = matches.is_present("show-excluded");
-
-    Config::new(
-        communicator,
-        pool,
-        date,
-        exclude_file,
-        show_queued,
-        show_excluded,
-        dry_run,
-        iteration_count,
-        no_confirm,
-        label,
-        show_config,
-    )
+    if removed_count < snapshots.len() {
+        process::exit(EXIT_CODE_INCOMPLETE);
+    }
 }
 
-/// Returns all the snapshots that will be deleted
-fn get_relevant_snapshots<T: Communicator>(
+/// Destroys each set of snapshots with a single recursive destroy of the
+/// snapshot of its topmost dataset. A set is never split, so the run stops
+/// before the first set that doesn't fit in its budget.
+///
+/// Example: zfs destroy -r tank@2020-08-23-1023-17-ANIMALS
+fn destroy_sets<'a, T: Communicator>(
     communicator: &T,
-    config: &Config,
-    excluded_snapshots: &Vec<Snapshot>,
-) -> Vec<Snapshot> {
-    let unparsed_snapshots = get_snapshots(communicator);
-    let parsed_snapshots = get_parsed_snapshots(unparsed_snapshots);
-    let snapshots = get_snapshots_for(&config.pool(), config.label(), parsed_snapshots);
-    let stale_snapshots = get_stale_snapshots(snapshots, &config.date());
-    remove_excluded_snapshots(stale_snapshots, &excluded_snapshots)
+    snapshots: &'a [Snapshot],
+    config: &DestroyConfig,
+) -> Vec<&'a Snapshot> {
+    let mut total_processed: u32 = 0;
+    let snapshot_count = snapshots.len() as u32;
+    let mut deleted_snapshots: Vec<&Snapshot> = Vec::new();
+    let start = Instant::now();
+
+    for (set, members) in get_snapshot_sets(snapshots) {
+        match get_remaining_budget(config, start, deleted_snapshots.len()) {
+            Some(budget) if members.len() <= budget => (),
+            _ => break,
+        }
+        println!("Cleaning snapshot set {} ...", set);
+        if let Err(e) = communicator.destroy_snapshot_recursively(&set) {
+            panic!("{:?}", e);
+        }
+        total_processed += members.len() as u32;
+        println!(
+            "Deleted | {:6.2}% <=> [{}/{}]\n",
+            calculate_percentage(total_processed, snapshot_count),
+            total_processed,
+            snapshot_count,
+        );
+        deleted_snapshots.extend(members);
+    }
+    print_remaining_snapshots(snapshots, &deleted_snapshots);
+    deleted_snapshots
 }
 
-fn remove_excluded_snapshots(
-    mut snapshots: Vec<Snapshot>,
-    excluded_snapshots: &Vec<Snapshot>,
-) -> Vec<Snapshot> {
-    for excluded_snapshot in excluded_snapshots {
-        snapshots.retain(|snapshot| snapshot != excluded_snapshot);
+/// Groups the snapshots into the sets that were taken together with
+/// zfs snapshot -r. A set holds the snapshots with the same name after the
+/// '@' in a dataset and its descendents, and is named after the snapshot of
+/// its topmost dataset.
+fn get_snapshot_sets(snapshots: &[Snapshot]) -> BTreeMap<String, Vec<&Snapshot>> {
+    let mut datasets_by_suffix: HashMap<&str, HashSet<&str>> = HashMap::new();
+    for snapshot in snapshots {
+        datasets_by_suffix
+            .entry(snapshot.suffix())
+            .or_default()
+            .insert(snapshot.dataset());
     }
-    snapshots
+
+    let mut sets: BTreeMap<String, Vec<&Snapshot>> = BTreeMap::new();
+    for snapshot in snapshots {
+        let datasets = &datasets_by_suffix[snapshot.suffix().as_str()];
+        let dataset = snapshot.dataset();
+        let root = dataset
+            .match_indices('/')
+            .map(|(index, _)| &dataset[..index])
+            .find(|ancestor| datasets.contains(ancestor))
+            .unwrap_or(dataset);
+        sets.entry(format!("{}@{}", root, snapshot.suffix()))
+            .or_default()
+            .push(snapshot);
+    }
+    sets
 }
 
-/// Retrieves all of the excluded snapshots that are relevant to this pool.
-fn get_excluded_snapshots<T: Communicator>(communicator: &T, config: &Config) -> Vec<Snapshot> {
-    let results = communicator.get_excluded_snapshots(config.exclude_file());
-    get_snapshots_for(
-        config.pool(),
-        config.label(),
-        get_parsed_snapshots(get_snapshots_base(results)),
-    )
+/// Keeps every stale snapshot whose set isn't entirely stale, since a set is
+/// only ever destroyed as a whole. The sets are made from all of the pool's
+/// snapshots, so a snapshot that is excluded, kept or filtered out protects
+/// the rest of its set. Returns the snapshots that can be destroyed, and the
+/// ones that are kept.
+fn keep_whole_sets(
+    stale_snapshots: Vec<Snapshot>,
+    pool_snapshots: &[Snapshot],
+) -> (Vec<Snapshot>, Vec<Snapshot>) {
+    let stale_names: HashSet<String> = stale_snapshots.iter().map(|s| s.to_string()).collect();
+    let partial_sets: HashSet<String> = get_snapshot_sets(pool_snapshots)
+        .into_iter()
+        .filter(|(_, members)| {
+            members
+                .iter()
+                .any(|member| !stale_names.contains(&member.to_string()))
+        })
+        .flat_map(|(_, members)| members.into_iter().map(|member| member.to_string()))
+        .collect();
+    stale_snapshots
+        .into_iter()
+        .partition(|snapshot| !partial_sets.contains(&snapshot.to_string()))
 }
 
-fn get_snapshots_for(pool: &str, label: &str, snapshots: Vec<Snapshot>) -> Vec<Snapshot> {
-    if label.is_empty() {
-        snapshots
-            .into_iter()
-            .filter(|snapshot| snapshot.pool() == pool)
-            .collect()
+/// Returns the creation order of the snapshots if ranges should be used.
+fn get_order_for<T: Communicator>(communicator: &T, config: &Config) -> Option<SnapshotOrder> {
+    if config.use_ranges() {
+        Some(get_snapshot_order(communicator))
     } else {
-        snapshots
-            .into_iter()
-            .filter(|snapshot| snapshot.pool() == pool && snapshot.label() == label)
-            .collect()
+        None
     }
 }
 
-/// Parses a string into proper Snapshot struct.
-/// Returns None if it failed to be parsed.
-/// Format: boot@2020-08-12-1237-49-CHECKPOINT
-fn parse_snapshot(snapshot: &str) -> Option<Snapshot> {
-    // Split the main two sections [name / time-label]
-    let initial_split: Vec<_> = snapshot.split("@").collect();
-
-    if initial_split.len() != 2 {
-        return None;
+fn get_snapshot_order<T: Communicator>(communicator: &T) -> SnapshotOrder {
+    let mut order = SnapshotOrder::new();
+    for line in get_snapshots_base(communicator.get_snapshot_order()) {
+        if let Some((dataset, name)) = split_snapshot_name(&line) {
+            order
+                .entry(dataset.to_string())
+                .or_default()
+                .push(name.to_string());
+        }
     }
+    order
+}
 
-    // Extract the pool and dataset name
-    let name_splinters: Vec<_> = initial_split[0].split("/").collect();
-    let pool = name_splinters[0];
-    let dataset = initial_split[0];
-
-    // Extract the time and label
-    let date_label_splinters: Vec<_> = initial_split[1].split("-").collect();
-
-    if date_label_splinters.len() != 6 {
-        return None;
+/// Destroys all the snapshots of a pool in a single transaction by running
+/// a channel program, instead of sending batches to zfs destroy. Since the
+/// program can't be stopped once it runs, it only gets the snapshots that
+/// fit in the deletion budget, in the order they would be deleted in.
+fn destroy_with_channel_program<'a, T: Communicator>(
+    communicator: &T,
+    config: &Config,
+    snapshots: &'a [Snapshot],
+) -> Result<Vec<&'a Snapshot>, SystemError> {
+    let mut snapshots_to_delete: Vec<&Snapshot> =
+        get_ordered_batches(snapshots, &config.destroy_config())
+            .into_iter()
+            .flat_map(|(_, batch)| batch)
+            .collect();
+    if let Some(max_deletions) = config.max_deletions() {
+        snapshots_to_delete.truncate(max_deletions);
     }
-    let label = date_label_splinters[date_label_splinters.len() - 1];
-
-    let mut date_string = String::new();
-
-    // year + month + day + time + second
-    date_string.push_str(date_label_splinters[0]);
-    date_string.push_str("-");
-    date_string.push_str(date_label_splinters[1]);
-    date_string.push_str("-");
-    date_string.push_str(date_label_splinters[2]);
-    date_string.push_str("-");
-    date_string.push_str(date_label_splinters[3]);
-    date_string.push_str("-");
-    date_string.push_str(date_label_splinters[4]);
-
-    let date = match Local.datetime_from_str(&date_string, SNAPSHOT_FORMAT) {
-        Ok(d) => d,
-        Err(_) => {
-            println!("[Warning] Invalid Time Format. Skipping: {}", snapshot);
-            return None;
-        }
-    };
 
-    Some(Snapshot::new(pool, dataset, date, label))
+    println!("Cleaning snapshots for {} ...\n", config.pool());
+    communicator.run_channel_program(
+        config.pool(),
+        &build_channel_program(&snapshots_to_delete),
+        config.instruction_limit(),
+        config.memory_limit(),
+    )?;
+    let deleted_count = snapshots_to_delete.len() as u32;
+    let snapshot_count = snapshots.len() as u32;
+    println!(
+        "Deleted | {:6.2}% <=> [{}/{}]\n",
+        calculate_percentage(deleted_count, snapshot_count),
+        deleted_count,
+        snapshot_count,
+    );
+    print_remaining_snapshots(snapshots, &snapshots_to_delete);
+    Ok(snapshots_to_delete)
 }
 
-fn get_parsed_snapshots(unparsed_snapshots: Vec<String>) -> Vec<Snapshot> {
-    let mut parsed_snapshots: Vec<Snapshot> = Vec::new();
-    for us in unparsed_snapshots {
-        if let Some(parsed_snapshot) = parse_snapshot(&us) {
-            parsed_snapshots.push(parsed_snapshot);
-        }
+/// Builds a channel program that destroys the snapshots. It checks that
+/// every snapshot can be destroyed before destroying any of them, and
+/// fails with the ones that can't along with their error codes otherwise.
+fn build_channel_program(snapshots: &[&Snapshot]) -> String {
+    let mut program = String::new();
+    program.push_str(&format!("-- Generated by {} {}\n", APP_NAME, APP_VERSION));
+    program.push_str("snapshots = {\n");
+    for snapshot in snapshots {
+        program.push_str(&format!("    {},\n", quote_lua(&snapshot.to_string())));
     }
-    parsed_snapshots
+    program.push_str("}\n");
+    program.push_str(
+        r#"failed = {}
+for _, snapshot in ipairs(snapshots) do
+    err = zfs.check.destroy(snapshot)
+    if err ~= 0 then
+        failed[snapshot] = err
+    end
+end
+if next(failed) ~= nil then
+    message = "Can't destroy:"
+    for snapshot, err in pairs(failed) do
+        message = message .. " " .. snapshot .. " (" .. err .. ")"
+    end
+    error(message)
+end
+for _, snapshot in ipairs(snapshots) do
+    zfs.sync.destroy(snapshot)
+end
+return #snapshots
+"#,
+    );
+    program
 }
 
-fn get_stale_snapshots(snapshots: Vec<Snapshot>, cutoff_date: &DateTime<Local>) -> Vec<Snapshot> {
-    snapshots
-        .into_iter()
-        .filter(|snapshot| snapshot.is_stale(cutoff_date))
-        .collect()
+/// Quotes the text as a Lua string.
+fn quote_lua(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
 }
 
-fn get_snapshots<T: Communicator>(communicator: &T) -> Vec<String> {
-    get_snapshots_base(communicator.get_snapshots())
+/// Renames the snapshots to their trash name so that they can still be
+/// recovered until they are purged, until the budget of the run is used up.
+/// Returns the new names.
+fn trash_snapshots<T: Communicator>(
+    communicator: &T,
+    snapshots: &[Snapshot],
+    time: DateTime<Local>,
+    config: &DestroyConfig,
+) -> Vec<String> {
+    let mut trashed_snapshots: Vec<String> = Vec::new();
+    let mut renamed_snapshots: Vec<&Snapshot> = Vec::new();
+    let snapshot_count = snapshots.len() as u32;
+    let start = Instant::now();
+    for snapshot in snapshots {
+        if get_remaining_budget(config, start, renamed_snapshots.len()).is_none() {
+            break;
+        }
+        let trash_name = snapshot.trash_name(time);
+        if let Err(e) = communicator.rename_snapshot(&snapshot.to_string(), &trash_name) {
+            panic!("{:?}", e);
+        }
+        trashed_snapshots.push(trash_name);
+        renamed_snapshots.push(snapshot);
+
+        let total_processed = trashed_snapshots.len() as u32;
+        println!(
+            "Trashed | {:6.2}% <=> [{}/{}]",
+            calculate_percentage(total_processed, snapshot_count),
+            total_processed,
+            snapshot_count,
+        );
+    }
+    print_remaining_snapshots(snapshots, &renamed_snapshots);
+    trashed_snapshots
 }
 
-fn get_snapshots_base(results: SystemResult) -> Vec<String> {
-    let results = match results {
-        Err(e) => panic!("{:?}", e),
-        Ok(v) => v,
-    };
+// Integration Tested Only
+/// Destroys the snapshots that have been in the trash for longer than the
+/// grace period.
+fn purge<T: Communicator>(communicator: &T, config: &PurgeConfig, time: DateTime<Local>) {
+    config.print();
 
-    let mut snapshots: Vec<String> = Vec::new();
+    let trashed_snapshots = get_trashed_snapshots(get_snapshots(communicator, &[]), config.pool());
+    let purgeable_snapshots =
+        get_purgeable_snapshots(trashed_snapshots, time - config.grace_duration());
 
-    for r in results.lines() {
-        snapshots.push(r.to_string());
+    println!("These snapshots are QUEUED for PURGING:");
+    println!("----------------");
+    for snapshot in &purgeable_snapshots {
+        println!("{}", snapshot);
     }
+    println!();
+    println!(
+        "Amount of Snapshots to Purge: {}",
+        purgeable_snapshots.len()
+    );
+    println!();
 
-    snapshots
+    if config.should_dry_run() {
+        return;
+    }
+    if purgeable_snapshots.is_empty() {
+        println!("Your trash is already empty. Take care!");
+        return;
+    }
+    if !config.no_confirm() {
+        let input = ask("Do you want to delete the above snapshots? [y/N]: ");
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing will be deleted. Take care!");
+            return;
+        }
+    }
+    destroy_snapshots(
+        communicator,
+        &purgeable_snapshots,
+        None,
+        &DestroyConfig::new(
+            config.iteration_count(),
+            get_default_max_argument_length(communicator),
+            DEFAULT_JOBS,
+            DeletionOrder::Dataset,
+            None,
+            None,
+        ),
+    );
 }
 
-fn build_list_to_delete(snapshots: &Vec<&Snapshot>) -> String {
-    let mut names = String::new();
-    for (index, snapshot) in snapshots.iter().enumerate() {
-        if names.is_empty() {
-            names.push_str(&snapshot.to_string());
-        } else {
-            names.push_str(snapshot.suffix().as_str());
-        }
+/// Returns the snapshots in the pool that were moved to the trash. The
+/// listing is parsed by creation date, since the names of trashed
+/// snapshots don't need to carry a date of their own.
+fn get_trashed_snapshots(unparsed_snapshots: Vec<String>, pool: &str) -> Vec<Snapshot> {
+    let unparsed_snapshots = unparsed_snapshots
+        .into_iter()
+        .filter(|line| line.split('\t').next().unwrap_or("").contains(TRASH_MARKER))
+        .collect();
+    get_listed_snapshots(
+        unparsed_snapshots,
+        &TimestampSource::Creation,
+        DEFAULT_CREATION_TOLERANCE,
+        false,
+    )
+    .into_iter()
+    .filter(|snapshot| snapshot.pool() == pool && snapshot.is_trashed())
+    .collect()
+}
 
-        if index + 1 != snapshots.len() {
-            names.push_str(",");
-        }
-    }
-    names
+/// Returns the trashed snapshots that were moved to the trash before the
+/// cutoff date.
+fn get_purgeable_snapshots(
+    snapshots: Vec<Snapshot>,
+    cutoff_date: DateTime<Local>,
+) -> Vec<Snapshot> {
+    snapshots
+        .into_iter()
+        .filter(|snapshot| {
+            snapshot
+                .trashed()
+                .is_some_and(|trashed| trashed < cutoff_date)
+        })
+        .collect()
 }
 
-/// Builds the list of snapshots to destroy and destroys them.
-fn build_and_destroy<'a, T: Communicator>(
+/// Writes the commands that would destroy the snapshots to a shell script
+/// instead of running them.
+fn emit_script<T: Communicator>(
     communicator: &T,
-    snapshots: &Vec<&'a Snapshot>,
-    numerator: u32,
-    denominator: u32,
-) -> Vec<&'a Snapshot> {
-    let deleted_snapshots = match communicator.destroy_snapshots(build_list_to_delete(&snapshots)) {
-        Err(e) => panic!("{:?}", e),
-        Ok(_) => {
-            let mut deleted_snapshots: Vec<&Snapshot> = Vec::new();
-            for snapshot in snapshots {
-                deleted_snapshots.push(snapshot);
-            }
-            deleted_snapshots
-        }
+    config: &Config,
+    snapshots: &[Snapshot],
+    time: DateTime<Local>,
+) -> SystemResult {
+    let script = if config.recursive_sets() {
+        build_set_script(config.pool(), snapshots, time)
+    } else {
+        build_script(
+            config.pool(),
+            snapshots,
+            get_order_for(communicator, config).as_ref(),
+            &config.destroy_config(),
+            time,
+        )
     };
-
-    let percent_completed = calculate_percentage(numerator, denominator);
+    let results = communicator.write_script(config.emit_script(), &script)?;
     println!(
-        "Deleted | {:6.2}% <=> [{}/{}]",
-        percent_completed, numerator, denominator,
+        "Scripted | {} snapshots were written to {}",
+        snapshots.len(),
+        config.emit_script()
     );
-    deleted_snapshots
+    Ok(results)
 }
 
-fn get_datasets(snapshots: &Vec<Snapshot>) -> HashSet<String> {
-    let mut datasets = HashSet::new();
-
-    for snapshot in snapshots {
-        if !datasets.contains(snapshot.dataset()) {
-            datasets.insert(snapshot.dataset().clone());
+/// Builds a shell script with the same zfs destroy commands that
+/// destroy_snapshots would run. Each batch first checks that all of its
+/// snapshots still exist, and the script stops at the first one that
+/// doesn't.
+fn build_script(
+    pool: &str,
+    snapshots: &[Snapshot],
+    order: Option<&SnapshotOrder>,
+    config: &DestroyConfig,
+    time: DateTime<Local>,
+) -> String {
+    let mut script = get_script_header(pool, snapshots.len(), time);
+    let mut current_dataset: Option<&String> = None;
+    for (dataset, batch) in get_ordered_batches(snapshots, config) {
+        if current_dataset != Some(dataset) {
+            script.push_str(&format!("\n# Cleaning snapshots for {}\n", dataset));
+            current_dataset = Some(dataset);
         }
+        let names: Vec<String> = batch
+            .iter()
+            .map(|snapshot| quote_shell(&snapshot.to_string()))
+            .collect();
+        script.push_str(&format!(
+            "zfs list -H -o name -t snapshot {} > /dev/null\n",
+            names.join(" ")
+        ));
+        let dataset_order = order.and_then(|order| order.get(dataset));
+        script.push_str(&format!(
+            "zfs destroy {}\n",
+            quote_shell(&build_list_to_delete(&batch, dataset_order))
+        ));
     }
+    script
+}
 
-    datasets
+/// Builds a shell script with the same recursive zfs destroy commands that
+/// destroy_sets would run. Each set first checks that all of its snapshots
+/// still exist.
+fn build_set_script(pool: &str, snapshots: &[Snapshot], time: DateTime<Local>) -> String {
+    let mut script = get_script_header(pool, snapshots.len(), time);
+    for (set, members) in get_snapshot_sets(snapshots) {
+        let names: Vec<String> = members
+            .iter()
+            .map(|snapshot| quote_shell(&snapshot.to_string()))
+            .collect();
+        script.push_str(&format!("\n# Cleaning snapshot set {}\n", set));
+        script.push_str(&format!(
+            "zfs list -H -o name -t snapshot {} > /dev/null\n",
+            names.join(" ")
+        ));
+        script.push_str(&format!("zfs destroy -r {}\n", quote_shell(&set)));
+    }
+    script
 }
 
-fn get_cutoff_date(time: DateTime<Local>) -> DateTime<Local> {
-    const DEFAULT_CUTOFF: i64 = 30;
-    time - Duration::days(DEFAULT_CUTOFF)
+fn get_script_header(pool: &str, amount: usize, time: DateTime<Local>) -> String {
+    let mut script = String::new();
+    script.push_str("#!/bin/sh\n");
+    script.push_str(&format!(
+        "# Generated by {} {} at {}\n",
+        APP_NAME,
+        APP_VERSION,
+        time.format(SNAPSHOT_FORMAT)
+    ));
+    script.push_str(&format!("# Pool: {}\n", pool));
+    script.push_str(&format!("# Amount of Snapshots to Remove: {}\n", amount));
+    script.push_str("set -e\n");
+    script
 }
 
-/// Calculates the percentage complete
-fn calculate_percentage(numerator: u32, denominator: u32) -> f32 {
-    numerator as f32 / denominator as f32 * 100.0
+/// Quotes the text so that the shell passes it through as is.
+fn quote_shell(text: &str) -> String {
+    format!("'{}'", text.replace('\'', "'\\''"))
 }
 
-/// Destroys the ZFS snapshots.
-///
-/// For faster deletions, zfs will be sent a list of snapshots in zfs' desired
-/// format in order to send a bigger batch to zfs at a time.
-///
-/// Example: zfs destroy <dataset>@<label1>,<label2>,<label3>
-fn destroy_snapshots<'a, T: Communicator>(
+/// Writes the snapshots that would be cleaned to the plan file instead of
+/// deleting them. Since a plan can be applied without confirmation, it isn't
+/// written when it goes over the mass deletion limits, unless they are
+/// allowed. Returns whether the plan was written.
+fn plan<T: Communicator>(
     communicator: &T,
-    snapshots: &'a Vec<Snapshot>,
-    iteration_amount: u32,
-) -> Vec<&'a Snapshot> {
-    let mut total_processed: u32 = 0;
-    let snapshot_count = snapshots.len() as u32;
-    let mut queued_snapshots: Vec<&Snapshot> = Vec::new();
-    let mut deleted_snapshots: Vec<&Snapshot> = Vec::new();
+    config: &Config,
+    plan_file: &str,
+    time: DateTime<Local>,
+) -> Result<bool, SystemError> {
+    let (stale_snapshots, mass_deletion) = queue_snapshots(communicator, config);
+    if mass_deletion.is_some() && !config.allow_mass_deletion() {
+        println!("[Error] Refusing to plan this many snapshots. Use --allow-mass-deletion if you mean it. Nothing will be written.");
+        return Ok(false);
+    }
+    let names = stale_snapshots.iter().map(|s| s.to_string()).collect();
+    let plan = Plan::new(
+        config.pool(),
+        time,
+        config.exclude_file(),
+        config.protect_property(),
+        names,
+    );
+    communicator.write_plan(plan_file, &plan.to_json())?;
+    println!(
+        "Planned | {} snapshots were written to {}",
+        plan.snapshots().len(),
+        plan_file
+    );
+    Ok(true)
+}
 
-    let cleaner = |total_processed: &mut u32,
-                   queued_snapshots: &mut Vec<&'a Snapshot>,
-                   communicator: &T,
-                   snapshot_count: u32,
-                   deleted_snapshots: &mut Vec<&'a Snapshot>| {
-        *total_processed += queued_snapshots.len() as u32;
-        build_and_destroy(
+// Integration Tested Only
+/// Deletes exactly the snapshots in the plan, after checking that the plan
+/// is still valid.
+fn apply<T: Communicator>(communicator: &T, config: &ApplyConfig, time: DateTime<Local>) {
+    config.print();
+
+    let contents = match communicator.get_plan(config.plan_file()) {
+        Err(e) => panic!("{:?}", e),
+        Ok(v) => v,
+    };
+    let plan = match Plan::from_json(&contents) {
+        Err(e) => panic!("Invalid plan: {}", e),
+        Ok(v) => v,
+    };
+    let planned_snapshots = match get_planned_snapshots(communicator, &plan, config, time) {
+        Err(e) => panic!("{} Nothing will be deleted.", e),
+        Ok(v) => v,
+    };
+
+    println!("These snapshots are PLANNED for REMOVAL:");
+    println!("----------------");
+    for snapshot in &planned_snapshots {
+        println!("{}", snapshot);
+    }
+    println!();
+    println!("Pool: {}", plan.pool());
+    println!("Planned At: {}", plan.created().format(SNAPSHOT_FORMAT));
+    println!("Amount of Snapshots to Remove: {}", planned_snapshots.len());
+    println!();
+
+    if config.should_dry_run() {
+        return;
+    }
+    if planned_snapshots.is_empty() {
+        println!("Your pool is already clean. Take care!");
+        return;
+    }
+    if !config.no_confirm() {
+        let input = ask("Do you want to delete the above snapshots? [y/N]: ");
+        if !input.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing will be deleted. Take care!");
+            return;
+        }
+    }
+    destroy_snapshots(
+        communicator,
+        &planned_snapshots,
+        None,
+        &DestroyConfig::new(
+            config.iteration_count(),
+            get_default_max_argument_length(communicator),
+            DEFAULT_JOBS,
+            DeletionOrder::Dataset,
+            None,
+            None,
+        ),
+    );
+}
+
+/// Returns the snapshots in the plan. Returns the reason if the plan is too
+/// old, or if any of its snapshots no longer exist or were pinned since the
+/// plan was made.
+fn get_planned_snapshots<T: Communicator>(
+    communicator: &T,
+    plan: &Plan,
+    config: &ApplyConfig,
+    time: DateTime<Local>,
+) -> Result<Vec<Snapshot>, String> {
+    if time - *plan.created() > config.max_plan_duration() {
+        return Err(format!(
+            "The plan is stale (It was made at {}, which is more than {} ago).",
+            plan.created().format(SNAPSHOT_FORMAT),
+            config.max_plan_age()
+        ));
+    }
+
+    // The protect property is listed after the creation time.
+    let existing_snapshots: HashMap<String, Option<Pin>> =
+        get_snapshots(communicator, &[plan.protect_property()])
+            .iter()
+            .map(|line| {
+                let columns: Vec<&str> = line.split('\t').collect();
+                let protection = columns.get(2).and_then(|value| parse_protection(value));
+                (columns[0].to_string(), protection)
+            })
+            .collect();
+    let missing_snapshots: Vec<&String> = plan
+        .snapshots()
+        .iter()
+        .filter(|name| !existing_snapshots.contains_key(*name))
+        .collect();
+    if !missing_snapshots.is_empty() {
+        for name in &missing_snapshots {
+            println!("[Error] Snapshot doesn't exist: {}", name);
+        }
+        return Err(format!(
+            "The plan refers to {} snapshots that no longer exist.",
+            missing_snapshots.len()
+        ));
+    }
+
+    let mut planned_snapshots: Vec<Snapshot> = Vec::new();
+    for name in plan.snapshots() {
+        match split_snapshot_name(name) {
+            Some((dataset, suffix)) if get_pool(dataset) == plan.pool() => {
+                let mut snapshot =
+                    Snapshot::from_creation(plan.pool(), dataset, suffix, "", *plan.created());
+                snapshot.set_protection(existing_snapshots[name].clone());
+                planned_snapshots.push(snapshot);
+            }
+            _ => return Err(format!("The plan refers to another pool: {}", name)),
+        }
+    }
+
+    let exclusions = if plan.exclude_file().is_empty() {
+        Vec::new()
+    } else {
+        get_exclusions(&get_exclude_lines(communicator, plan.exclude_file()))
+    };
+    let today = time.naive_local().date();
+    let protected_snapshots = get_excluded_snapshots(&planned_snapshots, &exclusions, today);
+    if !protected_snapshots.is_empty() {
+        for snapshot in &protected_snapshots {
+            println!(
+                "[Error] Snapshot is protected: {}",
+                format_excluded_snapshot(snapshot, &exclusions, today)
+            );
+        }
+        return Err(format!(
+            "The plan refers to {} snapshots that are now protected.",
+            protected_snapshots.len()
+        ));
+    }
+    Ok(planned_snapshots)
+}
+
+// Integration Tested Only
+/// Prints the question and returns the answer that was typed.
+fn ask(question: &str) -> String {
+    print!("{}", question);
+    io::stdout().flush().unwrap();
+    let mut input = String::new();
+    match io::stdin().read_line(&mut input) {
+        Ok(_) => (),
+        Err(e) => panic!("Invalid Input. Exiting. Error: {}", e),
+    };
+    println!();
+    input
+}
+
+// Integration Tested Only
+/// Finds and reports the snapshots that will be deleted for the configured
+/// pool. Also returns the reason if that's more than the deletion limits.
+fn queue_snapshots<T: Communicator>(
+    communicator: &T,
+    config: &Config,
+) -> (Vec<Snapshot>, Option<String>) {
+    config.print();
+
+    let exclude_lines = if config.exclude_file().is_empty() {
+        Vec::new()
+    } else {
+        get_exclude_lines(communicator, config.exclude_file())
+    };
+
+    let parsed_snapshots = get_parsed_snapshots(communicator, config);
+    let problems = get_exclude_problems(&exclude_lines, config, &parsed_snapshots);
+    if !problems.is_empty() {
+        println!("These entries in the exclude file have PROBLEMS:");
+        println!("----------------");
+        for problem in &problems {
+            println!("{}", problem);
+        }
+        println!();
+
+        if config.strict_excludes() {
+            panic!(
+                "Found {} problems in the exclude file. Nothing will be deleted.",
+                problems.len()
+            );
+        }
+    }
+
+    let pool_snapshots: Vec<Snapshot> = parsed_snapshots
+        .iter()
+        .filter(|snapshot| snapshot.pool() == config.pool())
+        .cloned()
+        .collect();
+    let now = Local::now();
+    let today = now.naive_local().date();
+    let exclusions = get_exclusions(&exclude_lines);
+    let snapshots = get_snapshots_for(
+        config.pool(),
+        config.labels(),
+        config.excluded_labels(),
+        config.ignore_case(),
+        parsed_snapshots,
+    );
+    let (snapshots, dataset_filters) = filter_datasets(
+        snapshots,
+        config.include_datasets(),
+        config.exclude_datasets(),
+    );
+    let excluded_snapshots = get_excluded_snapshots(&snapshots, &exclusions, today);
+    let expired_snapshots = get_expired_snapshots(&snapshots, &exclusions, today);
+    let (stale_snapshots, newest_snapshots) =
+        get_relevant_snapshots(snapshots, config, &exclusions, now);
+    let (stale_snapshots, partial_set_snapshots) = if config.recursive_sets() {
+        keep_whole_sets(stale_snapshots, &pool_snapshots)
+    } else {
+        (stale_snapshots, Vec::new())
+    };
+
+    if config.should_show_queued() {
+        println!("These snapshots are QUEUED for REMOVAL:");
+        println!("----------------");
+        for snapshot_to_delete in &stale_snapshots {
+            if snapshot_to_delete.is_stale(config.cutoff_date_for(snapshot_to_delete.label())) {
+                println!("{}", snapshot_to_delete);
+            } else {
+                println!("{} (Expired)", snapshot_to_delete);
+            }
+        }
+        println!();
+    }
+
+    if config.should_show_excluded() {
+        println!("These snapshots are EXCLUDED from REMOVAL:");
+        println!("----------------");
+        for snapshot_to_exclude in excluded_snapshots.iter().chain(&expired_snapshots) {
+            println!(
+                "{}",
+                format_excluded_snapshot(snapshot_to_exclude, &exclusions, today)
+            );
+        }
+        println!();
+    }
+
+    if config.should_show_excluded() && !newest_snapshots.is_empty() {
+        println!("These snapshots are KEPT as the NEWEST of their DATASET and LABEL:");
+        println!("----------------");
+        for snapshot_to_keep in &newest_snapshots {
+            println!("{}", snapshot_to_keep);
+        }
+        println!();
+    }
+
+    if config.should_show_excluded() && !partial_set_snapshots.is_empty() {
+        println!("These snapshots are KEPT because the rest of their SET is KEPT:");
+        println!("----------------");
+        for snapshot_to_keep in &partial_set_snapshots {
+            println!("{}", snapshot_to_keep);
+        }
+        println!();
+    }
+
+    println!("Amount of Snapshots to Remove: {}", stale_snapshots.len());
+    if !config.max_ages().is_empty() {
+        for (max_age, amount) in count_by_max_age(&stale_snapshots, config) {
+            println!("Amount of Snapshots to Remove ({}): {}", max_age, amount);
+        }
+    }
+    println!(
+        "Amount of Snapshots to Exclude: {}",
+        excluded_snapshots.len()
+    );
+    println!(
+        "Amount of Snapshots to Keep (Newest): {}",
+        newest_snapshots.len()
+    );
+    if config.recursive_sets() {
+        println!(
+            "Amount of Snapshots to Keep (Partial Sets): {}",
+            partial_set_snapshots.len()
+        );
+    }
+    for (rule, amount) in &dataset_filters {
+        println!("Amount of Snapshots Filtered ({}): {}", rule, amount);
+    }
+    println!();
+
+    let mass_deletion = get_mass_deletion(stale_snapshots.len(), pool_snapshots.len(), config);
+    if let Some(reason) = &mass_deletion {
+        println!("[Warning] {}", reason);
+        println!();
+    }
+
+    (stale_snapshots, mass_deletion)
+}
+
+// Integration Tested Only
+/// Parses the command line arguments and returns the action to perform
+/// along with its configuration.
+///
+/// This function will panic if you pass it an exclude file that does not exist.
+pub fn parse_arguments<T: Communicator>(communicator: &T) -> Action {
+    let matches = App::new(APP_NAME)
+        .version(APP_VERSION)
+        .author(APP_AUTHOR)
+        .about(APP_DESCRIPTION)
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .args(&get_clean_arguments())
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Takes a snapshot named after the current time and a label.")
+                .arg(
+                    Arg::with_name("pool")
+                        .short("p")
+                        .long("pool")
+                        .help("The pool or dataset you want to snapshot. Can be given multiple times.")
+                        .required(true)
+                        .multiple(true)
+                        .number_of_values(1)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("label")
+                        .short("l")
+                        .long("label")
+                        .help("The label of the snapshot.")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .long("recursive")
+                        .help("Also snapshots all descendent datasets."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("rotate")
+                .about("Takes a snapshot and then cleans the stale snapshots with the same label.")
+                .args(&get_clean_arguments())
+                .arg(
+                    Arg::with_name("recursive")
+                        .short("r")
+                        .long("recursive")
+                        .help("Also snapshots all descendent datasets."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("plan")
+                .about("Saves the snapshots that would be cleaned to a plan, so that they can be reviewed and applied later.")
+                .args(&get_clean_arguments())
+                .arg(
+                    Arg::with_name("output")
+                        .short("o")
+                        .long("output")
+                        .help("The file the plan is written to.")
+                        .required(true)
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("apply")
+                .about("Deletes exactly the snapshots in a plan.")
+                .arg(
+                    Arg::with_name("plan")
+                        .help("The plan you want to apply.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Performs a dry run. No deletions will occur."),
+                )
+                .arg(
+                    Arg::with_name("per-iteration")
+                        .short("i")
+                        .long("per-iteration")
+                        .help("Number of snapshots to delete per iteration.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-confirm")
+                        .short("f")
+                        .long("no-confirm")
+                        .help("Deletes snapshots without confirmation."),
+                )
+                .arg(
+                    Arg::with_name("max-plan-age")
+                        .long("max-plan-age")
+                        .help("Refuses to apply plans that are older than this (Example: 24h).")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("purge")
+                .about("Deletes the snapshots that have been in the trash for longer than the grace period.")
+                .arg(
+                    Arg::with_name("pool")
+                        .short("p")
+                        .long("pool")
+                        .help("The pool you want to purge.")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("grace-period")
+                        .short("g")
+                        .long("grace-period")
+                        .help("How long snapshots stay in the trash before they are purged (Example: 7d).")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("dry-run")
+                        .short("n")
+                        .long("dry-run")
+                        .help("Performs a dry run. No deletions will occur."),
+                )
+                .arg(
+                    Arg::with_name("per-iteration")
+                        .short("i")
+                        .long("per-iteration")
+                        .help("Number of snapshots to delete per iteration.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("no-confirm")
+                        .short("f")
+                        .long("no-confirm")
+                        .help("Deletes snapshots without confirmation."),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pin")
+                .about("Protects a snapshot from removal.")
+                .arg(
+                    Arg::with_name("snapshot")
+                        .help("The snapshot you want to protect.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("exclude-file")
+                        .short("e")
+                        .long("exclude-file")
+                        .help("Adds the snapshot to this file instead of setting the protect property.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("owner")
+                        .short("o")
+                        .long("owner")
+                        .help("Who the snapshot is pinned for.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("reason")
+                        .short("r")
+                        .long("reason")
+                        .help("Why the snapshot is pinned.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .short("u")
+                        .long("until")
+                        .help("The last day the snapshot is pinned for (Example: 2020-12-31).")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("protect-property")
+                        .long("protect-property")
+                        .help("The user property that protects a snapshot from removal.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("unpin")
+                .about("Removes the protection from a snapshot.")
+                .arg(
+                    Arg::with_name("snapshot")
+                        .help("The snapshot you want to unprotect.")
+                        .required(true)
+                        .index(1),
+                )
+                .arg(
+                    Arg::with_name("exclude-file")
+                        .short("e")
+                        .long("exclude-file")
+                        .help("Removes the snapshot from this file instead of the protect property.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("protect-property")
+                        .long("protect-property")
+                        .help("The user property that protects a snapshot from removal.")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("pins")
+                .about("Lists the protected snapshots for a pool.")
+                .arg(
+                    Arg::with_name("pool")
+                        .short("p")
+                        .long("pool")
+                        .help("The pool you want to list the protected snapshots for.")
+                        .required(true)
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("exclude-file")
+                        .short("e")
+                        .long("exclude-file")
+                        .help("Also lists the entries in this file.")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("protect-property")
+                        .long("protect-property")
+                        .help("The user property that protects a snapshot from removal.")
+                        .takes_value(true),
+                ),
+        )
+        .get_matches();
+
+    if let Some(matches) = matches.subcommand_matches("pin") {
+        return Action::Pin(get_pin_config(matches));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("unpin") {
+        return Action::Unpin(get_pin_config(matches));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("pins") {
+        return Action::Pins(get_pin_config(matches));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("plan") {
+        let plan_file = matches.value_of("output").unwrap();
+        return Action::Plan(get_config(communicator, matches), plan_file.to_string());
+    }
+
+    if let Some(matches) = matches.subcommand_matches("apply") {
+        let plan_file = matches.value_of("plan").unwrap();
+        let dry_run = matches.is_present("dry-run");
+        let iteration_count: u32 = match matches.value_of("per-iteration") {
+            Some(v) => v.parse().unwrap(),
+            None => DEFAULT_ITERATIONS,
+        };
+        let no_confirm = matches.is_present("no-confirm");
+        let max_plan_age = matches
+            .value_of("max-plan-age")
+            .unwrap_or(DEFAULT_MAX_PLAN_AGE);
+        return Action::Apply(ApplyConfig::new(
             communicator,
-            &queued_snapshots,
-            *total_processed,
-            snapshot_count,
+            plan_file,
+            dry_run,
+            iteration_count,
+            no_confirm,
+            max_plan_age,
+        ));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("purge") {
+        let pool = matches.value_of("pool").unwrap();
+        let grace_period = matches
+            .value_of("grace-period")
+            .unwrap_or(DEFAULT_GRACE_PERIOD);
+        let dry_run = matches.is_present("dry-run");
+        let iteration_count: u32 = match matches.value_of("per-iteration") {
+            Some(v) => v.parse().unwrap(),
+            None => DEFAULT_ITERATIONS,
+        };
+        let no_confirm = matches.is_present("no-confirm");
+        return Action::Purge(PurgeConfig::new(
+            pool,
+            grace_period,
+            dry_run,
+            iteration_count,
+            no_confirm,
+        ));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("snapshot") {
+        let datasets = matches
+            .values_of("pool")
+            .unwrap()
+            .map(|dataset| dataset.to_string())
+            .collect();
+        let label = matches.value_of("label").unwrap();
+        let recursive = matches.is_present("recursive");
+
+        return Action::Snapshot(SnapshotConfig::new(datasets, label, recursive));
+    }
+
+    if let Some(matches) = matches.subcommand_matches("rotate") {
+        let pool = matches.value_of("pool").unwrap();
+        let label = matches.value_of("label").unwrap_or("");
+        let recursive = matches.is_present("recursive");
+        let snapshot_config = SnapshotConfig::new(vec![pool.to_string()], label, recursive);
+
+        return Action::Rotate(snapshot_config, get_config(communicator, matches));
+    }
+
+    Action::Clean(get_config(communicator, &matches))
+}
+
+/// Returns the arguments that configure the cleaning of a pool.
+fn get_clean_arguments<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("pool")
+            .short("p")
+            .long("pool")
+            .help("The pool you want to clean.")
+            .required(true)
+            .takes_value(true),
+        Arg::with_name("date")
+            .short("d")
+            .long("date")
+            .help(
+                "The slice date that you want to use as your end point for snapshot deletions.",
+            )
+            .takes_value(true),
+        Arg::with_name("exclude-file")
+            .short("e")
+            .long("exclude-file")
+            .help("Excludes the snapshots matching this file (one snapshot or pattern per line).")
+            .takes_value(true),
+        Arg::with_name("show-queued")
+            .short("s")
+            .long("show-queued")
+            .help("Show snapshots that will be removed."),
+        Arg::with_name("show-excluded")
+            .short("x")
+            .long("show-excluded")
+            .help("Show snapshots that will be excluded."),
+        Arg::with_name("dry-run")
+            .short("n")
+            .long("dry-run")
+            .help("Performs a dry run. No deletions will occur."),
+        Arg::with_name("per-iteration")
+            .short("i")
+            .long("per-iteration")
+            .help("Number of snapshots to delete per iteration.")
+            .takes_value(true),
+        Arg::with_name("no-confirm")
+            .short("f")
+            .long("no-confirm")
+            .help("Deletes snapshots without confirmation. Used primarily for cron."),
+        Arg::with_name("label")
+            .short("l")
+            .long("label")
+            .help("The labels of the snapshots that should be cleaned (Comma separated, and may use '*' and '?').")
+            .takes_value(true),
+        Arg::with_name("exclude-label")
+            .long("exclude-label")
+            .help("The labels of the snapshots that should never be cleaned (Comma separated, and may use '*' and '?').")
+            .takes_value(true),
+        Arg::with_name("ignore-case")
+            .long("ignore-case")
+            .help("Matches the labels regardless of case."),
+        Arg::with_name("show-config")
+            .short("c")
+            .long("show-config")
+            .help("Displays the full configuration options used by the application."),
+        Arg::with_name("timestamp-source")
+            .short("t")
+            .long("timestamp-source")
+            .help("Where the age of a snapshot is taken from: its name or its creation property.")
+            .possible_values(&["name", "creation"])
+            .takes_value(true),
+        Arg::with_name("anchor")
+            .long("anchor")
+            .help("What the age of a snapshot is measured against: now, or the newest snapshot of its dataset or pool.")
+            .possible_values(&["now", "dataset", "pool"])
+            .takes_value(true),
+        Arg::with_name("creation-tolerance")
+            .long("creation-tolerance")
+            .help("Warns when a snapshot's name and creation dates differ by more than this many seconds.")
+            .takes_value(true),
+        Arg::with_name("protect-property")
+            .long("protect-property")
+            .help("The user property that protects a snapshot from removal when set to 'on'.")
+            .takes_value(true),
+        Arg::with_name("expires-property")
+            .long("expires-property")
+            .help("The user property that holds a snapshot's own expiry (A date or a lifetime such as '7d').")
+            .takes_value(true),
+        Arg::with_name("max-age")
+            .long("max-age")
+            .help("How old the snapshots with a label can get before they are cleaned (Comma separated. Example: HOURLY=2d,DAILY=30d).")
+            .takes_value(true),
+        Arg::with_name("include-dataset")
+            .long("include-dataset")
+            .help("Only cleans the datasets matching this pattern. Can be given multiple times.")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("exclude-dataset")
+            .long("exclude-dataset")
+            .help("Never cleans the datasets matching this pattern. Can be given multiple times.")
+            .multiple(true)
+            .number_of_values(1)
+            .takes_value(true),
+        Arg::with_name("keep-newest")
+            .short("k")
+            .long("keep-newest")
+            .help("Always keeps this many of the newest snapshots of each dataset and label, regardless of their age. Use 0 to turn it off.")
+            .takes_value(true),
+        Arg::with_name("max-delete-percentage")
+            .long("max-delete-percentage")
+            .help("Refuses to delete more than this percentage of the pool's snapshots without typing the pool's name.")
+            .takes_value(true),
+        Arg::with_name("max-delete-count")
+            .long("max-delete-count")
+            .help("Refuses to delete more than this many snapshots without typing the pool's name.")
+            .takes_value(true),
+        Arg::with_name("allow-mass-deletion")
+            .long("allow-mass-deletion")
+            .help("Deletes the snapshots even if there are more than the above limits."),
+        Arg::with_name("jobs")
+            .short("j")
+            .long("jobs")
+            .help("Cleans this many datasets at once.")
+            .takes_value(true),
+        Arg::with_name("max-runtime")
+            .long("max-runtime")
+            .help("Stops deleting between batches once the run has taken this long, and exits with 4 (Example: 30m).")
+            .takes_value(true),
+        Arg::with_name("max-deletions")
+            .long("max-deletions")
+            .help("Stops deleting once this many snapshots were deleted, and exits with 4.")
+            .takes_value(true),
+        Arg::with_name("order")
+            .long("order")
+            .help("The order the snapshots are deleted in: dataset by dataset, or the oldest snapshots first.")
+            .possible_values(&["dataset", "oldest"])
+            .takes_value(true),
+        Arg::with_name("max-argument-length")
+            .long("max-argument-length")
+            .help("The longest list of snapshots given to zfs destroy at once, in bytes (Defaults to half of the system's ARG_MAX).")
+            .takes_value(true),
+        Arg::with_name("ranges")
+            .long("ranges")
+            .help("Destroys the runs of queued snapshots that have nothing else in between as ranges (first%last)."),
+        Arg::with_name("recursive-sets")
+            .long("recursive-sets")
+            .help("Treats the snapshots with the same name in a dataset and its descendents as one set, that is kept or destroyed (With zfs destroy -r) as a whole."),
+        Arg::with_name("emit-script")
+            .long("emit-script")
+            .help("Writes the zfs destroy commands to this shell script instead of running them.")
+            .takes_value(true),
+        Arg::with_name("quarantine")
+            .long("quarantine")
+            .help("Renames the stale snapshots to a trash label instead of deleting them. Use purge to delete them later."),
+        Arg::with_name("backend")
+            .long("backend")
+            .help("How the snapshots are destroyed: in batches of zfs destroy, or all at once in a single channel program.")
+            .possible_values(&["batched", "channel-program"])
+            .takes_value(true),
+        Arg::with_name("instruction-limit")
+            .long("instruction-limit")
+            .help("The instruction limit of the channel program (Defaults to the limit of zfs program).")
+            .takes_value(true),
+        Arg::with_name("memory-limit")
+            .long("memory-limit")
+            .help("The memory limit of the channel program in bytes (Defaults to the limit of zfs program).")
+            .takes_value(true),
+        Arg::with_name("config-file")
+            .short("C")
+            .long("config-file")
+            .help("Reads additional options from this file (One option and its value per line).")
+            .takes_value(true),
+        Arg::with_name("label-expiry")
+            .long("label-expiry")
+            .help("Also cleans snapshots that are past the lifetime in their label (Example: KEEP7D)."),
+        Arg::with_name("strict-excludes")
+            .long("strict-excludes")
+            .help("Aborts if any entry in the exclude file has a problem."),
+    ]
+}
+
+/// Builds the cleaning configuration from the parsed arguments.
+fn get_config<T: Communicator>(communicator: &T, matches: &ArgMatches) -> Config {
+    let pool = matches.value_of("pool").unwrap();
+    let label = matches.value_of("label").unwrap_or("");
+    let exclude_label = matches.value_of("exclude-label").unwrap_or("");
+    let ignore_case = matches.is_present("ignore-case");
+    let exclude_file = matches.value_of("exclude-file").unwrap_or("");
+    let config_file = matches.value_of("config-file").unwrap_or("");
+    let emit_script = matches.value_of("emit-script").unwrap_or("");
+    let max_age = matches.value_of("max-age").unwrap_or("");
+    let get_values = |name: &str| -> Vec<String> {
+        match matches.values_of(name) {
+            Some(values) => values.map(|value| value.to_string()).collect(),
+            None => Vec::new(),
+        }
+    };
+    let include_datasets = get_values("include-dataset");
+    let exclude_datasets = get_values("exclude-dataset");
+    let keep_newest: usize = match matches.value_of("keep-newest") {
+        Some(v) => v.parse().unwrap(),
+        None => DEFAULT_KEEP_NEWEST,
+    };
+    let show_config = matches.is_present("show-config");
+    let date = matches.value_of("date").unwrap_or("");
+    let no_confirm = matches.is_present("no-confirm");
+    let iteration_count: u32 = match matches.value_of("per-iteration") {
+        Some(v) => v.parse().unwrap(),
+        None => DEFAULT_ITERATIONS,
+    };
+    let max_argument_length: Option<usize> = matches
+        .value_of("max-argument-length")
+        .map(|v| v.parse().unwrap());
+    let jobs: usize = match matches.value_of("jobs") {
+        Some(v) => v.parse().unwrap(),
+        None => DEFAULT_JOBS,
+    };
+    let max_runtime = matches.value_of("max-runtime").unwrap_or("");
+    let max_deletions: Option<usize> = matches
+        .value_of("max-deletions")
+        .map(|v| v.parse().unwrap());
+    let deletion_order = match matches.value_of("order") {
+        Some("oldest") => DeletionOrder::Oldest,
+        _ => DeletionOrder::Dataset,
+    };
+    let dry_run = matches.is_present("dry-run");
+    let show_queued = matches.is_present("show-queued");
+    let show_excluded = matches.is_present("show-excluded");
+    let timestamp_source = match matches.value_of("timestamp-source") {
+        Some("creation") => TimestampSource::Creation,
+        _ => TimestampSource::Name,
+    };
+    let max_delete_percentage: Option<f32> = matches
+        .value_of("max-delete-percentage")
+        .map(|v| v.parse().unwrap());
+    let max_delete_count: Option<usize> = matches
+        .value_of("max-delete-count")
+        .map(|v| v.parse().unwrap());
+    let allow_mass_deletion = matches.is_present("allow-mass-deletion");
+    let quarantine = matches.is_present("quarantine");
+    let use_ranges = matches.is_present("ranges");
+    let recursive_sets = matches.is_present("recursive-sets");
+    let backend = match matches.value_of("backend") {
+        Some("channel-program") => Backend::ChannelProgram,
+        _ => Backend::Batched,
+    };
+    let instruction_limit: Option<u64> = matches
+        .value_of("instruction-limit")
+        .map(|v| v.parse().unwrap());
+    let memory_limit: Option<u64> = matches.value_of("memory-limit").map(|v| v.parse().unwrap());
+    let cutoff_anchor = match matches.value_of("anchor") {
+        Some("dataset") => CutoffAnchor::Dataset,
+        Some("pool") => CutoffAnchor::Pool,
+        _ => CutoffAnchor::Now,
+    };
+    let creation_tolerance: i64 = match matches.value_of("creation-tolerance") {
+        Some(v) => v.parse().unwrap(),
+        None => DEFAULT_CREATION_TOLERANCE,
+    };
+    let strict_excludes = matches.is_present("strict-excludes");
+    let label_expiry = matches.is_present("label-expiry");
+    let protect_property = matches
+        .value_of("protect-property")
+        .unwrap_or(DEFAULT_PROTECT_PROPERTY);
+    let expires_property = matches
+        .value_of("expires-property")
+        .unwrap_or(DEFAULT_EXPIRES_PROPERTY);
+
+    Config::new(
+        communicator,
+        Options {
+            pool: pool.to_string(),
+            date: date.to_string(),
+            exclude_file: exclude_file.to_string(),
+            config_file: config_file.to_string(),
+            emit_script: emit_script.to_string(),
+            show_queued,
+            show_excluded,
+            dry_run,
+            iteration_count,
+            max_argument_length,
+            jobs,
+            deletion_order,
+            max_runtime: max_runtime.to_string(),
+            max_deletions,
+            no_confirm,
+            label: label.to_string(),
+            exclude_label: exclude_label.to_string(),
+            ignore_case,
+            max_age: max_age.to_string(),
+            include_datasets,
+            exclude_datasets,
+            keep_newest,
+            max_delete_percentage,
+            max_delete_count,
+            allow_mass_deletion,
+            quarantine,
+            use_ranges,
+            recursive_sets,
+            backend,
+            instruction_limit,
+            memory_limit,
+            show_config,
+            timestamp_source,
+            cutoff_anchor,
+            creation_tolerance,
+            strict_excludes,
+            label_expiry,
+            protect_property: protect_property.to_string(),
+            expires_property: expires_property.to_string(),
+        },
+    )
+}
+
+/// Builds the pin configuration from the parsed arguments.
+fn get_pin_config(matches: &ArgMatches) -> PinConfig {
+    let pool = matches.value_of("pool").unwrap_or("");
+    let snapshot = matches.value_of("snapshot").unwrap_or("");
+    let exclude_file = matches.value_of("exclude-file").unwrap_or("");
+    let owner = matches.value_of("owner").unwrap_or("");
+    let reason = matches.value_of("reason").unwrap_or("");
+    let until = matches.value_of("until").unwrap_or("");
+    let protect_property = matches
+        .value_of("protect-property")
+        .unwrap_or(DEFAULT_PROTECT_PROPERTY);
+
+    PinConfig::new(
+        pool,
+        snapshot,
+        exclude_file,
+        Pin::new(owner, reason, until),
+        protect_property,
+    )
+}
+
+/// Takes the snapshots described by the configuration. The snapshots for
+/// all of the datasets are created atomically by ZFS.
+fn take_snapshots<T: Communicator>(
+    communicator: &T,
+    config: &SnapshotConfig,
+    time: DateTime<Local>,
+) -> SystemResult {
+    config.print();
+
+    let snapshots = get_snapshot_names(config.datasets(), config.label(), time);
+    let results = communicator.create_snapshots(&snapshots, config.recursive())?;
+
+    for snapshot in &snapshots {
+        println!("Created | {}", snapshot);
+    }
+    println!();
+    Ok(results)
+}
+
+/// Returns the snapshot names for the datasets in the accepted format.
+/// Format: boot@2020-08-12-1237-49-CHECKPOINT
+fn get_snapshot_names(datasets: &[String], label: &str, time: DateTime<Local>) -> Vec<String> {
+    datasets
+        .iter()
+        .map(|dataset| format!("{}@{}-{}", dataset, time.format(SNAPSHOT_FORMAT), label))
+        .collect()
+}
+
+/// Pins the snapshot in the exclude file, or with the protect property if
+/// no exclude file was given. The exclude file is created if it doesn't
+/// exist yet.
+fn pin<T: Communicator>(communicator: &T, config: &PinConfig) -> SystemResult {
+    config.print();
+    check_snapshot_exists(communicator, config.snapshot());
+
+    if config.exclude_file().is_empty() {
+        let value = format!("on {}", config.pin());
+        let results = communicator.set_property(
+            config.snapshot(),
+            config.protect_property(),
+            value.trim(),
+        )?;
+        println!("Pinned | {}", config.snapshot());
+        return Ok(results);
+    }
+
+    let mut exclude_lines = if communicator.does_file_exist(config.exclude_file()) {
+        get_exclude_lines(communicator, config.exclude_file())
+    } else {
+        Vec::new()
+    };
+    if exclude_lines
+        .iter()
+        .any(|line| is_exact_entry(line, config.snapshot()))
+    {
+        println!("Already Pinned | {}", config.snapshot());
+        return Ok(String::new());
+    }
+
+    let entry = format!("{} {}", config.snapshot(), config.pin());
+    exclude_lines.push(entry.trim().to_string());
+    let results = communicator
+        .write_excluded_snapshots(config.exclude_file(), &(exclude_lines.join("\n") + "\n"))?;
+    println!("Pinned | {}", config.snapshot());
+    Ok(results)
+}
+
+/// Unpins the snapshot from the exclude file, or from the protect property
+/// if no exclude file was given. Entries in the exclude file can be removed
+/// even if the snapshot no longer exists.
+fn unpin<T: Communicator>(communicator: &T, config: &PinConfig) -> SystemResult {
+    config.print();
+
+    if config.exclude_file().is_empty() {
+        check_snapshot_exists(communicator, config.snapshot());
+        let results =
+            communicator.inherit_property(config.snapshot(), config.protect_property())?;
+        println!("Unpinned | {}", config.snapshot());
+        return Ok(results);
+    }
+
+    check_exclude_file_exists(communicator, config.exclude_file());
+    let exclude_lines = get_exclude_lines(communicator, config.exclude_file());
+    let remaining_lines: Vec<String> = exclude_lines
+        .iter()
+        .filter(|line| !is_exact_entry(line, config.snapshot()))
+        .cloned()
+        .collect();
+
+    if remaining_lines.len() == exclude_lines.len() {
+        println!("Not Pinned | {}", config.snapshot());
+        return Ok(String::new());
+    }
+
+    let mut contents = remaining_lines.join("\n");
+    if !contents.is_empty() {
+        contents.push('\n');
+    }
+    let results = communicator.write_excluded_snapshots(config.exclude_file(), &contents)?;
+    println!("Unpinned | {}", config.snapshot());
+    Ok(results)
+}
+
+/// Checks if the line in the exclude file is an entry for exactly this snapshot.
+fn is_exact_entry(line: &str, snapshot: &str) -> bool {
+    match parse_exclusion(line) {
+        Ok(Some(exclusion)) => {
+            matches!(exclusion.pattern(), Pattern::Exact(name) if name == snapshot)
+        }
+        _ => false,
+    }
+}
+
+/// Panics if the snapshot isn't listed by ZFS.
+fn check_snapshot_exists<T: Communicator>(communicator: &T, snapshot: &str) {
+    let exists = get_snapshots(communicator, &[])
+        .iter()
+        .any(|line| line.split('\t').next() == Some(snapshot));
+
+    if !exists {
+        panic!("Snapshot doesn't exist: {}", snapshot);
+    }
+}
+
+/// Panics if the exclude file doesn't exist.
+fn check_exclude_file_exists<T: Communicator>(communicator: &T, exclude_file: &str) {
+    if !communicator.does_file_exist(exclude_file) {
+        panic!("File doesn't exist: {}", exclude_file);
+    }
+}
+
+// Integration Tested Only
+/// Lists the pins in the exclude file (if given) and the snapshots that
+/// are protected by the protect property for the pool.
+fn list_pins<T: Communicator>(communicator: &T, config: &PinConfig) {
+    config.print();
+    let today = Local::now().naive_local().date();
+
+    if !config.exclude_file().is_empty() {
+        check_exclude_file_exists(communicator, config.exclude_file());
+        let exclude_lines = get_exclude_lines(communicator, config.exclude_file());
+        println!("These entries are PINNED in the EXCLUDE FILE:");
+        println!("----------------");
+        for exclusion in get_file_pins(&exclude_lines, config.pool()) {
+            println!(
+                "{}",
+                format_pin(&exclusion.pattern().to_string(), exclusion.pin(), today)
+            );
+        }
+        println!();
+    }
+
+    let lines = get_snapshots(communicator, &[config.protect_property()]);
+    println!("These snapshots are PINNED by the PROTECT PROPERTY:");
+    println!("----------------");
+    for (name, pin) in get_property_pins(&lines, config.pool()) {
+        println!("{}", format_pin(&name, &pin, today));
+    }
+    println!();
+}
+
+/// Returns the entries in the exclude file that may apply to the pool.
+fn get_file_pins(exclude_lines: &[String], pool: &str) -> Vec<Exclusion> {
+    get_exclusions(exclude_lines)
+        .into_iter()
+        .filter(|exclusion| match get_pattern_pool(exclusion.pattern()) {
+            Some(pattern_pool) => pattern_pool == pool,
+            None => true,
+        })
+        .collect()
+}
+
+/// Returns the names of the snapshots in the pool that are protected by
+/// their property, along with their pin. The names are taken as listed, so
+/// that snapshots without a date in their name are included.
+fn get_property_pins(lines: &[String], pool: &str) -> Vec<(String, Pin)> {
+    lines
+        .iter()
+        .filter_map(|line| {
+            // The protect property is listed after the creation time.
+            let columns: Vec<&str> = line.split('\t').collect();
+            let (dataset, _) = split_snapshot_name(columns[0])?;
+            if get_pool(dataset) != pool {
+                return None;
+            }
+            let pin = parse_protection(columns.get(2)?)?;
+            Some((columns[0].to_string(), pin))
+        })
+        .collect()
+}
+
+fn format_pin(name: &str, pin: &Pin, today: NaiveDate) -> String {
+    let mut text = name.to_string();
+    if !pin.owner().is_empty() {
+        text.push_str(&format!(" | Owner: {}", pin.owner()));
+    }
+    if let Some(until) = pin.until() {
+        text.push_str(&format!(" | Until: {}", until.format(PIN_DATE_FORMAT)));
+        if pin.is_expired(today) {
+            text.push_str(" (Expired)");
+        }
+    }
+    if !pin.reason().is_empty() {
+        text.push_str(&format!(" | Reason: {}", pin.reason()));
+    }
+    text
+}
+
+/// Returns all the snapshots listed by ZFS that are in the accepted format.
+fn get_parsed_snapshots<T: Communicator>(communicator: &T, config: &Config) -> Vec<Snapshot> {
+    let unparsed_snapshots = get_snapshots(
+        communicator,
+        &[config.protect_property(), config.expires_property()],
+    );
+    get_listed_snapshots(
+        unparsed_snapshots,
+        config.timestamp_source(),
+        config.creation_tolerance(),
+        config.label_expiry(),
+    )
+}
+
+/// Returns all the snapshots that will be deleted, and the ones that would
+/// have been deleted but are kept as the newest of their dataset and label.
+fn get_relevant_snapshots(
+    snapshots: Vec<Snapshot>,
+    config: &Config,
+    exclusions: &[Exclusion],
+    now: DateTime<Local>,
+) -> (Vec<Snapshot>, Vec<Snapshot>) {
+    let newest_snapshots = get_newest_snapshots(&snapshots, config.keep_newest());
+    let stale_snapshots = get_stale_snapshots(snapshots, config, &now);
+    let stale_snapshots =
+        remove_excluded_snapshots(stale_snapshots, exclusions, now.naive_local().date());
+    stale_snapshots
+        .into_iter()
+        .partition(|snapshot| !newest_snapshots.contains(snapshot.to_string().as_str()))
+}
+
+/// Returns the names of the newest snapshots of each dataset and label.
+fn get_newest_snapshots(snapshots: &[Snapshot], keep: usize) -> HashSet<String> {
+    let mut groups: HashMap<(&str, &str), Vec<&Snapshot>> = HashMap::new();
+    for snapshot in snapshots {
+        groups
+            .entry((snapshot.dataset(), snapshot.label()))
+            .or_default()
+            .push(snapshot);
+    }
+
+    let mut newest_snapshots: HashSet<String> = HashSet::new();
+    for group in groups.values_mut() {
+        group.sort_by(|a, b| b.date().cmp(a.date()));
+        newest_snapshots.extend(group.iter().take(keep).map(|s| s.to_string()));
+    }
+    newest_snapshots
+}
+
+fn remove_excluded_snapshots(
+    mut snapshots: Vec<Snapshot>,
+    exclusions: &[Exclusion],
+    today: NaiveDate,
+) -> Vec<Snapshot> {
+    snapshots.retain(|snapshot| !is_excluded(snapshot, exclusions, today));
+    snapshots
+}
+
+/// Checks if the snapshot is protected by its property or matched by
+/// any of the exclusions, and that protection hasn't expired yet.
+fn is_excluded(snapshot: &Snapshot, exclusions: &[Exclusion], today: NaiveDate) -> bool {
+    get_longest_pin(snapshot, exclusions).is_some_and(|pin| !pin.is_expired(today))
+}
+
+/// Returns the pin that protects the snapshot for the longest time, out of
+/// its protection property and all of the exclusions that match it.
+fn get_longest_pin<'a>(snapshot: &'a Snapshot, exclusions: &'a [Exclusion]) -> Option<&'a Pin> {
+    let name = snapshot.to_string();
+    snapshot
+        .protection()
+        .iter()
+        .chain(
+            exclusions
+                .iter()
+                .filter(|exclusion| exclusion.matches(&name))
+                .map(|exclusion| exclusion.pin()),
+        )
+        .max_by_key(|pin| (pin.until().is_none(), pin.until()))
+}
+
+/// Returns the snapshots that are protected by the exclusions.
+fn get_excluded_snapshots(
+    snapshots: &[Snapshot],
+    exclusions: &[Exclusion],
+    today: NaiveDate,
+) -> Vec<Snapshot> {
+    snapshots
+        .iter()
+        .filter(|snapshot| is_excluded(snapshot, exclusions, today))
+        .cloned()
+        .collect()
+}
+
+/// Returns the snapshots that were pinned, but whose pins have all expired.
+fn get_expired_snapshots(
+    snapshots: &[Snapshot],
+    exclusions: &[Exclusion],
+    today: NaiveDate,
+) -> Vec<Snapshot> {
+    snapshots
+        .iter()
+        .filter(|snapshot| {
+            get_longest_pin(snapshot, exclusions).is_some_and(|pin| pin.is_expired(today))
+        })
+        .cloned()
+        .collect()
+}
+
+fn format_excluded_snapshot(
+    snapshot: &Snapshot,
+    exclusions: &[Exclusion],
+    today: NaiveDate,
+) -> String {
+    let mut text = snapshot.to_string();
+    if snapshot.is_protected() {
+        text.push_str(" (Protected)");
+    }
+    if let Some(pin) = get_longest_pin(snapshot, exclusions) {
+        match (pin.until(), pin.days_left(today)) {
+            (Some(until), _) if pin.is_expired(today) => {
+                text.push_str(&format!(" (EXPIRED on {})", until.format(PIN_DATE_FORMAT)))
+            }
+            (Some(until), Some(days)) => text.push_str(&format!(
+                " (Until {}: {} day(s) left)",
+                until.format(PIN_DATE_FORMAT),
+                days
+            )),
+            _ => (),
+        }
+    }
+    text
+}
+
+fn get_exclude_lines<T: Communicator>(communicator: &T, exclude_file: &str) -> Vec<String> {
+    get_snapshots_base(communicator.get_excluded_snapshots(exclude_file))
+}
+
+/// Returns the exclusion patterns for the lines that could be parsed.
+fn get_exclusions(exclude_lines: &[String]) -> Vec<Exclusion> {
+    exclude_lines
+        .iter()
+        .filter_map(|line| parse_exclusion(line).ok().flatten())
+        .collect()
+}
+
+/// Checks every line in the exclude file and returns the problems found.
+/// Entries that can't be parsed, that refer to another pool, or that don't
+/// match any of the pool's snapshots (Regardless of label) are reported.
+fn get_exclude_problems(
+    exclude_lines: &[String],
+    config: &Config,
+    snapshots: &[Snapshot],
+) -> Vec<ExcludeProblem> {
+    let mut problems: Vec<ExcludeProblem> = Vec::new();
+    let names: Vec<String> = snapshots
+        .iter()
+        .filter(|snapshot| snapshot.pool() == config.pool())
+        .map(|snapshot| snapshot.to_string())
+        .collect();
+
+    for (index, line) in exclude_lines.iter().enumerate() {
+        let line_number = index + 1;
+        let entry = line.trim().to_string();
+        let exclusion = match parse_exclusion(line) {
+            Ok(Some(exclusion)) => exclusion,
+            Ok(None) => continue,
+            Err(reason) => {
+                problems.push(ExcludeProblem::Unparseable(line_number, entry, reason));
+                continue;
+            }
+        };
+
+        let pattern = exclusion.pattern();
+        if let Pattern::Exact(name) = pattern {
+            // Snapshots without a date in their name are only listed when
+            // their timestamp comes from the creation property.
+            if config.timestamp_source() == &TimestampSource::Name && !is_dated_name(name) {
+                let reason = "Invalid snapshot format or date".to_string();
+                problems.push(ExcludeProblem::Unparseable(line_number, entry, reason));
+                continue;
+            }
+        }
+
+        if let Some(pool) = get_pattern_pool(pattern) {
+            if pool != config.pool() {
+                problems.push(ExcludeProblem::OtherPool(line_number, entry));
+                continue;
+            }
+        }
+
+        if !names.iter().any(|name| pattern.matches(name)) {
+            problems.push(ExcludeProblem::Unmatched(line_number, entry));
+        }
+    }
+
+    problems
+}
+
+/// Returns the pool an exact or glob pattern refers to if it's not
+/// itself a wildcard.
+fn get_pattern_pool(pattern: &Pattern) -> Option<&str> {
+    let text = match pattern {
+        Pattern::Exact(text) | Pattern::Glob(text) => text,
+        Pattern::Regex(_) => return None,
+    };
+    let pool = text.split(['/', '@']).next()?;
+
+    if pool.contains('*') || pool.contains('?') {
+        return None;
+    }
+    Some(pool)
+}
+
+/// Checks if the snapshot name is in the accepted format with a valid date.
+fn is_dated_name(snapshot: &str) -> bool {
+    match split_snapshot_name(snapshot).and_then(|(_, name)| split_date_label(name)) {
+        Some((date_string, _)) => Local
+            .datetime_from_str(&date_string, SNAPSHOT_FORMAT)
+            .is_ok(),
+        None => false,
+    }
+}
+
+/// Parses a line from the exclude file into an exclusion. The snapshot
+/// (or pattern) can be followed by the details of the pin.
+/// Returns None for blank lines and comments, and the reason if the line
+/// couldn't be parsed.
+///
+/// Formats:
+///   tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT (Exact)
+///   tank/vm/*@*                                  (Glob)
+///   re:^tank/.*@.*-RELEASE$                      (Regular Expression)
+///   tank@2020-07-13-2354-09-CHECKPOINT owner=jon # Before upgrading
+fn parse_exclusion(line: &str) -> Result<Option<Exclusion>, String> {
+    let line = line.trim();
+
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(None);
+    }
+
+    let (entry, details) = match line.find(char::is_whitespace) {
+        Some(index) => line.split_at(index),
+        None => (line, ""),
+    };
+    let pin = Pin::parse(details)?;
+
+    let pattern = if let Some(expression) = entry.strip_prefix("re:") {
+        match Regex::new(expression) {
+            Ok(regex) => Pattern::Regex(regex),
+            Err(_) => return Err("Invalid regular expression".to_string()),
+        }
+    } else if entry.contains('*') || entry.contains('?') {
+        Pattern::Glob(entry.to_string())
+    } else if split_snapshot_name(entry).is_some() {
+        Pattern::Exact(entry.to_string())
+    } else {
+        return Err("Not a snapshot name".to_string());
+    };
+
+    Ok(Some(Exclusion::new(pattern, pin)))
+}
+
+/// Matches the text against a glob pattern, where '*' matches any amount
+/// of characters and '?' matches exactly one.
+fn glob_matches(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            backtrack = Some((p, t));
+            p += 1;
+        } else if let Some((star, matched)) = backtrack {
+            // Let the last '*' swallow one more character and try again.
+            p = star + 1;
+            t = matched + 1;
+            backtrack = Some((star, t));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Returns the snapshots in the pool whose label matches any of the labels
+/// (Or all of them if no labels were given), and none of the excluded labels.
+/// Snapshots in the trash are left for purge.
+fn get_snapshots_for(
+    pool: &str,
+    labels: &[String],
+    excluded_labels: &[String],
+    ignore_case: bool,
+    snapshots: Vec<Snapshot>,
+) -> Vec<Snapshot> {
+    snapshots
+        .into_iter()
+        .filter(|snapshot| {
+            snapshot.pool() == pool
+                && !snapshot.is_trashed()
+                && (labels.is_empty() || label_matches_any(labels, snapshot.label(), ignore_case))
+                && !label_matches_any(excluded_labels, snapshot.label(), ignore_case)
+        })
+        .collect()
+}
+
+/// Removes the snapshots whose dataset doesn't match any of the included
+/// datasets (If any were given), or matches any of the excluded datasets.
+/// Returns the remaining snapshots and how many snapshots each rule removed.
+fn filter_datasets(
+    snapshots: Vec<Snapshot>,
+    include_datasets: &[String],
+    exclude_datasets: &[String],
+) -> (Vec<Snapshot>, Vec<(String, usize)>) {
+    let mut rules: Vec<(String, usize)> = Vec::new();
+    if !include_datasets.is_empty() {
+        rules.push((
+            format!("include-dataset {}", include_datasets.join(", ")),
+            0,
+        ));
+    }
+    let exclude_offset = rules.len();
+    for dataset in exclude_datasets {
+        rules.push((format!("exclude-dataset {}", dataset), 0));
+    }
+
+    let mut remaining_snapshots: Vec<Snapshot> = Vec::new();
+    for snapshot in snapshots {
+        let dataset = snapshot.dataset();
+        let rule = if !include_datasets.is_empty()
+            && !include_datasets.iter().any(|p| glob_matches(p, dataset))
+        {
+            Some(0)
+        } else {
+            exclude_datasets
+                .iter()
+                .position(|p| glob_matches(p, dataset))
+                .map(|index| exclude_offset + index)
+        };
+        match rule {
+            Some(index) => rules[index].1 += 1,
+            None => remaining_snapshots.push(snapshot),
+        }
+    }
+    (remaining_snapshots, rules)
+}
+
+/// Checks if the label matches any of the label patterns, optionally
+/// ignoring case.
+fn label_matches_any(patterns: &[String], label: &str, ignore_case: bool) -> bool {
+    if !ignore_case {
+        return patterns.iter().any(|pattern| glob_matches(pattern, label));
+    }
+    let label = label.to_uppercase();
+    patterns
+        .iter()
+        .any(|pattern| glob_matches(&pattern.to_uppercase(), &label))
+}
+
+/// Splits a comma separated list of labels.
+fn split_labels(labels: &str) -> Vec<String> {
+    labels
+        .split(',')
+        .map(|label| label.trim())
+        .filter(|label| !label.is_empty())
+        .map(|label| label.to_string())
+        .collect()
+}
+
+/// Parses a string into proper Snapshot struct.
+/// Returns None if it failed to be parsed.
+/// Format: boot@2020-08-12-1237-49-CHECKPOINT
+/// Trashed: boot@2020-08-12-1237-49-CHECKPOINT-TRASH-2020-08-23-1023-17
+fn parse_snapshot(snapshot: &str) -> Option<Snapshot> {
+    let (dataset, name) = split_snapshot_name(snapshot)?;
+    let (name, trashed) = split_trash_label(name);
+    let (date_string, label) = split_date_label(name)?;
+
+    let date = match Local.datetime_from_str(&date_string, SNAPSHOT_FORMAT) {
+        Ok(d) => d,
+        Err(_) => {
+            println!("[Warning] Invalid Time Format. Skipping: {}", snapshot);
+            return None;
+        }
+    };
+
+    let mut parsed_snapshot = Snapshot::new(get_pool(dataset), dataset, date, label);
+    if let Some(trashed) = trashed {
+        parsed_snapshot.set_trashed(trashed);
+    }
+    Some(parsed_snapshot)
+}
+
+/// Parses a snapshot whose timestamp is its creation date. The name does
+/// not need to carry a date, but if it does, it is expected to be within
+/// the tolerance (in seconds) of the creation date.
+fn parse_snapshot_with_creation(
+    snapshot: &str,
+    creation: DateTime<Local>,
+    tolerance: i64,
+) -> Option<Snapshot> {
+    let (dataset, name) = split_snapshot_name(snapshot)?;
+    let (name, trashed) = split_trash_label(name);
+    let mut label = "";
+
+    if let Some((date_string, name_label)) = split_date_label(name) {
+        if let Ok(date) = Local.datetime_from_str(&date_string, SNAPSHOT_FORMAT) {
+            label = name_label;
+            let difference = (date - creation).num_seconds().abs();
+            if difference > tolerance {
+                println!(
+                    "[Warning] Name and creation dates differ by {} seconds: {}",
+                    difference, snapshot
+                );
+            }
+        }
+    }
+
+    let mut parsed_snapshot =
+        Snapshot::from_creation(get_pool(dataset), dataset, name, label, creation);
+    if let Some(trashed) = trashed {
+        parsed_snapshot.set_trashed(trashed);
+    }
+    Some(parsed_snapshot)
+}
+
+/// Splits the trash label off the name of a snapshot, along with the time
+/// it was moved to the trash. Names without one are returned as is.
+/// Format: 2020-08-12-1237-49-CHECKPOINT-TRASH-2020-08-23-1023-17
+fn split_trash_label(name: &str) -> (&str, Option<DateTime<Local>>) {
+    if let Some(index) = name.rfind(TRASH_MARKER) {
+        let date_string = &name[index + TRASH_MARKER.len()..];
+        if let Ok(trashed) = Local.datetime_from_str(date_string, SNAPSHOT_FORMAT) {
+            return (&name[..index], Some(trashed));
+        }
+    }
+    (name, None)
+}
+
+/// Splits a snapshot into its dataset and the name after the '@'.
+fn split_snapshot_name(snapshot: &str) -> Option<(&str, &str)> {
+    // Split the main two sections [name / time-label]
+    let initial_split: Vec<_> = snapshot.split('@').collect();
+
+    if initial_split.len() != 2 {
+        return None;
+    }
+
+    Some((initial_split[0], initial_split[1]))
+}
+
+fn get_pool(dataset: &str) -> &str {
+    dataset.split('/').next().unwrap_or(dataset)
+}
+
+/// Splits the name of a snapshot into its date and label.
+/// Format: 2020-08-12-1237-49-CHECKPOINT
+fn split_date_label(name: &str) -> Option<(String, &str)> {
+    // Extract the time and label
+    let date_label_splinters: Vec<_> = name.split('-').collect();
+
+    if date_label_splinters.len() != 6 {
+        return None;
+    }
+    let label = date_label_splinters[date_label_splinters.len() - 1];
+
+    // year + month + day + time + second
+    let date_string = date_label_splinters[..5].join("-");
+
+    Some((date_string, label))
+}
+
+/// Parses the snapshot listing where each line is the snapshot name,
+/// optionally followed by tab separated columns for its creation time in
+/// seconds since the epoch and the value of its protection property.
+/// Snapshots without an expires property take their lifetime from their
+/// label when label expiry is turned on.
+fn get_listed_snapshots(
+    unparsed_snapshots: Vec<String>,
+    timestamp_source: &TimestampSource,
+    creation_tolerance: i64,
+    label_expiry: bool,
+) -> Vec<Snapshot> {
+    let mut parsed_snapshots: Vec<Snapshot> = Vec::new();
+    for us in unparsed_snapshots {
+        let columns: Vec<_> = us.split('\t').collect();
+        let name = columns[0];
+        let parsed_snapshot = match timestamp_source {
+            TimestampSource::Name => parse_snapshot(name),
+            TimestampSource::Creation => match columns.get(1).and_then(|c| c.parse::<i64>().ok()) {
+                Some(seconds) => parse_snapshot_with_creation(
+                    name,
+                    Local.timestamp(seconds, 0),
+                    creation_tolerance,
+                ),
+                None => {
+                    println!("[Warning] Missing Creation Date. Skipping: {}", name);
+                    None
+                }
+            },
+        };
+        if let Some(mut parsed_snapshot) = parsed_snapshot {
+            if let Some(protection) = columns.get(2) {
+                parsed_snapshot.set_protection(parse_protection(protection));
+            }
+            let creation = match columns.get(1).and_then(|c| c.parse::<i64>().ok()) {
+                Some(seconds) => Local.timestamp(seconds, 0),
+                None => *parsed_snapshot.date(),
+            };
+            let expiry = match columns.get(3).map(|c| c.trim()) {
+                Some(value) if !value.is_empty() && value != "-" => {
+                    let expiry = parse_expiry(value, creation);
+                    if expiry.is_none() {
+                        println!("[Warning] Invalid Expiry. Ignoring: {} ({})", name, value);
+                    }
+                    expiry
+                }
+                _ if label_expiry => parse_label_lifetime(parsed_snapshot.label())
+                    .map(|lifetime| creation + lifetime),
+                _ => None,
+            };
+            parsed_snapshot.set_expiry(expiry);
+            parsed_snapshots.push(parsed_snapshot);
+        }
+    }
+    parsed_snapshots
+}
+
+/// Parses the value of the expires property. It's either an absolute time
+/// (2020-12-31 or 2020-12-31-1200-00) or a lifetime counted from the
+/// snapshot's creation (7d).
+fn parse_expiry(value: &str, creation: DateTime<Local>) -> Option<DateTime<Local>> {
+    if let Some(lifetime) = parse_lifetime(value) {
+        return Some(creation + lifetime);
+    }
+    if let Ok(date) = Local.datetime_from_str(value, SNAPSHOT_FORMAT) {
+        return Some(date);
+    }
+    let date = NaiveDate::parse_from_str(value, PIN_DATE_FORMAT).ok()?;
+    Local
+        .from_local_date(&date)
+        .single()
+        .map(|d| d.and_hms(0, 0, 0))
+}
+
+/// Returns the lifetime carried by labels such as KEEP7D.
+fn parse_label_lifetime(label: &str) -> Option<Duration> {
+    match label.get(..4) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("KEEP") => parse_lifetime(&label[4..]),
+        _ => None,
+    }
+}
+
+/// Parses a lifetime such as 12h, 7d, 2w or 1y (365 days). Months aren't
+/// accepted, since 'm' means minutes for a runtime.
+fn parse_lifetime(value: &str) -> Option<Duration> {
+    let unit = value.chars().last()?;
+    // Small enough that the expiry can't overflow.
+    let amount: u16 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let amount = i64::from(amount);
+    match unit.to_ascii_lowercase() {
+        'h' => Some(Duration::hours(amount)),
+        'd' => Some(Duration::days(amount)),
+        'w' => Some(Duration::weeks(amount)),
+        'y' => Some(Duration::days(amount * 365)),
+        _ => None,
+    }
+}
+
+/// Parses a runtime such as 90s, 30m or 2h.
+fn parse_runtime(value: &str) -> Option<std::time::Duration> {
+    let unit = value.chars().last()?;
+    let amount: u32 = value[..value.len() - unit.len_utf8()].parse().ok()?;
+    let amount = u64::from(amount);
+    match unit.to_ascii_lowercase() {
+        's' => Some(std::time::Duration::from_secs(amount)),
+        'm' => Some(std::time::Duration::from_secs(amount * 60)),
+        'h' => Some(std::time::Duration::from_secs(amount * 60 * 60)),
+        _ => None,
+    }
+}
+
+/// Parses the value of the protection property. Returns None if the value
+/// doesn't turn protection on. ZFS lists unset properties as '-'.
+/// Format: on owner=jon # Before upgrading
+fn parse_protection(value: &str) -> Option<Pin> {
+    let value = value.trim();
+    let (state, details) = match value.find(char::is_whitespace) {
+        Some(index) => value.split_at(index),
+        None => (value, ""),
+    };
+
+    if !["on", "yes", "true"]
+        .iter()
+        .any(|on| state.eq_ignore_ascii_case(on))
+    {
+        return None;
+    }
+
+    // A snapshot that is turned on stays protected even if its details
+    // can't be parsed.
+    Some(Pin::parse(details).unwrap_or_default())
+}
+
+/// Returns the snapshots that are older than the cutoff date for their
+/// label, or that are past their own expiry.
+fn get_stale_snapshots(
+    snapshots: Vec<Snapshot>,
+    config: &Config,
+    now: &DateTime<Local>,
+) -> Vec<Snapshot> {
+    let anchors = get_anchors(&snapshots, config.cutoff_anchor());
+    snapshots
+        .into_iter()
+        .filter(|snapshot| {
+            let is_stale = match get_anchor_key(snapshot, config.cutoff_anchor())
+                .and_then(|key| anchors.get(key))
+            {
+                Some(anchor) => {
+                    snapshot.is_stale(&config.anchored_cutoff_date_for(snapshot.label(), anchor))
+                }
+                None => snapshot.is_stale(config.cutoff_date_for(snapshot.label())),
+            };
+            is_stale || snapshot.is_expired(now)
+        })
+        .collect()
+}
+
+/// Returns the date of the newest snapshot of each dataset or pool,
+/// depending on the anchor.
+fn get_anchors(
+    snapshots: &[Snapshot],
+    cutoff_anchor: &CutoffAnchor,
+) -> HashMap<String, DateTime<Local>> {
+    let mut anchors: HashMap<String, DateTime<Local>> = HashMap::new();
+    for snapshot in snapshots {
+        if let Some(key) = get_anchor_key(snapshot, cutoff_anchor) {
+            let anchor = anchors.entry(key.to_string()).or_insert(*snapshot.date());
+            if snapshot.date() > anchor {
+                *anchor = *snapshot.date();
+            }
+        }
+    }
+    anchors
+}
+
+fn get_anchor_key<'a>(snapshot: &'a Snapshot, cutoff_anchor: &CutoffAnchor) -> Option<&'a str> {
+    match cutoff_anchor {
+        CutoffAnchor::Now => None,
+        CutoffAnchor::Dataset => Some(snapshot.dataset()),
+        CutoffAnchor::Pool => Some(snapshot.pool()),
+    }
+}
+
+/// Counts the snapshots by the max age that applies to them. Snapshots
+/// that don't have one are counted under the cutoff date.
+fn count_by_max_age(snapshots: &[Snapshot], config: &Config) -> Vec<(String, usize)> {
+    let mut counts: Vec<(String, usize)> = config
+        .max_ages()
+        .iter()
+        .map(|max_age| (max_age.to_string(), 0))
+        .collect();
+    counts.push(("Cut Off Date".to_string(), 0));
+
+    for snapshot in snapshots {
+        let index = config
+            .max_ages()
+            .iter()
+            .position(|max_age| max_age.matches(snapshot.label(), config.ignore_case()))
+            .unwrap_or(counts.len() - 1);
+        counts[index].1 += 1;
+    }
+    counts
+}
+
+/// Parses the config file into its options and their values. Blank lines
+/// and lines starting with '#' are ignored.
+///
+/// Format: max-age HOURLY=2d
+fn get_config_entries(contents: &str) -> Vec<(String, String)> {
+    let mut entries: Vec<(String, String)> = Vec::new();
+    for (index, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (option, value) = match line.split_once(char::is_whitespace) {
+            Some((option, value)) if !value.trim().is_empty() => (option, value.trim()),
+            _ => panic!(
+                "Missing value in the config file (Line {}): {}",
+                index + 1,
+                line
+            ),
+        };
+        if !CONFIG_FILE_OPTIONS.contains(&option) {
+            panic!(
+                "Unknown option in the config file (Line {}): {}",
+                index + 1,
+                option
+            );
+        }
+        entries.push((option.to_string(), value.to_string()));
+    }
+    entries
+}
+
+fn get_snapshots<T: Communicator>(communicator: &T, properties: &[&str]) -> Vec<String> {
+    get_snapshots_base(communicator.get_snapshots(properties))
+}
+
+fn get_snapshots_base(results: SystemResult) -> Vec<String> {
+    let results = match results {
+        Err(e) => panic!("{:?}", e),
+        Ok(v) => v,
+    };
+
+    let mut snapshots: Vec<String> = Vec::new();
+
+    for r in results.lines() {
+        snapshots.push(r.to_string());
+    }
+
+    snapshots
+}
+
+/// Builds the list of snapshots in a dataset in the format zfs destroy
+/// expects. When the dataset's creation order is given, the snapshots are
+/// listed in that order, and runs of them are listed as ranges.
+///
+/// Example: tank@2020-07-13-2354-09-CHECKPOINT%2020-07-15-2354-09-CHECKPOINT,manual
+fn build_list_to_delete(snapshots: &[&Snapshot], order: Option<&Vec<String>>) -> String {
+    let suffixes: Vec<&str> = snapshots
+        .iter()
+        .map(|snapshot| snapshot.suffix().as_str())
+        .collect();
+    let entries = match order.and_then(|order| get_ranges(&suffixes, order)) {
+        Some(ranges) => ranges,
+        None => suffixes.iter().map(|suffix| suffix.to_string()).collect(),
+    };
+    match snapshots.first() {
+        Some(snapshot) => format!("{}@{}", snapshot.dataset(), entries.join(",")),
+        None => String::new(),
+    }
+}
+
+/// Groups the suffixes into runs that are next to each other in the
+/// creation order, and lists the runs of three or more as a range
+/// (first%last). The order must hold every snapshot in the dataset, so that
+/// a range never covers a snapshot that isn't queued. Returns None if any of
+/// the suffixes isn't in the order.
+fn get_ranges(suffixes: &[&str], order: &[String]) -> Option<Vec<String>> {
+    let positions: HashMap<&str, usize> = order
+        .iter()
+        .enumerate()
+        .map(|(position, suffix)| (suffix.as_str(), position))
+        .collect();
+    let mut queued_positions = suffixes
+        .iter()
+        .map(|suffix| positions.get(suffix).copied())
+        .collect::<Option<Vec<usize>>>()?;
+    queued_positions.sort_unstable();
+    queued_positions.dedup();
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut index = 0;
+    while index < queued_positions.len() {
+        let first = queued_positions[index];
+        let mut last = first;
+        while index + 1 < queued_positions.len() && queued_positions[index + 1] == last + 1 {
+            index += 1;
+            last += 1;
+        }
+        if last - first >= 2 {
+            ranges.push(format!("{}%{}", order[first], order[last]));
+        } else {
+            ranges.extend(order[first..=last].iter().cloned());
+        }
+        index += 1;
+    }
+    Some(ranges)
+}
+
+/// Builds the list of snapshots to destroy and destroys them.
+fn build_and_destroy<'a, T: Communicator>(
+    communicator: &T,
+    snapshots: &[&'a Snapshot],
+    order: Option<&Vec<String>>,
+    numerator: u32,
+    denominator: u32,
+) -> Vec<&'a Snapshot> {
+    let deleted_snapshots =
+        match communicator.destroy_snapshots(build_list_to_delete(snapshots, order)) {
+            Err(e) => panic!("{:?}", e),
+            Ok(_) => {
+                let mut deleted_snapshots: Vec<&Snapshot> = Vec::new();
+                for snapshot in snapshots {
+                    deleted_snapshots.push(snapshot);
+                }
+                deleted_snapshots
+            }
+        };
+
+    let percent_completed = calculate_percentage(numerator, denominator);
+    println!(
+        "Deleted | {:6.2}% <=> [{}/{}]",
+        percent_completed, numerator, denominator,
+    );
+    deleted_snapshots
+}
+
+/// Splits the snapshots into the batches that are sent to zfs, grouped by
+/// dataset, with the datasets sorted by name and their snapshots from oldest
+/// to newest.
+fn get_batches(
+    snapshots: &[Snapshot],
+    iteration_amount: u32,
+    max_argument_length: usize,
+) -> Vec<(String, Vec<Vec<&Snapshot>>)> {
+    let mut batches = Vec::new();
+    for dataset in get_datasets(snapshots) {
+        let mut snapshots_for_dataset: Vec<&Snapshot> = snapshots
+            .iter()
+            .filter(|snapshot| snapshot.dataset() == &dataset)
+            .collect();
+        snapshots_for_dataset.sort_by(|a, b| (a.date(), a.suffix()).cmp(&(b.date(), b.suffix())));
+        let dataset_batches = split_into_batches(
+            &snapshots_for_dataset,
+            iteration_amount,
+            max_argument_length,
+        );
+        batches.push((dataset, dataset_batches));
+    }
+    batches
+}
+
+/// Splits the snapshots of a dataset into batches, keeping their order.
+/// Snapshots deleted per round need to be all in the same dataset since it
+/// will be batched to ZFS for optimization, and there are at most the
+/// iteration amount of them. A batch is also split before its list of
+/// snapshots gets longer than the max argument length, unless it's a
+/// single snapshot.
+fn split_into_batches<'a>(
+    snapshots: &[&'a Snapshot],
+    iteration_amount: u32,
+    max_argument_length: usize,
+) -> Vec<Vec<&'a Snapshot>> {
+    let mut batches: Vec<Vec<&Snapshot>> = Vec::new();
+    let mut batch: Vec<&Snapshot> = Vec::new();
+    let mut batch_length = 0;
+    for snapshot in snapshots {
+        // The first snapshot is listed with its dataset, and the
+        // others only with their suffix after a comma.
+        let suffix_length = snapshot.suffix().len() + 1;
+        if !batch.is_empty()
+            && (batch.len() as u32 == iteration_amount
+                || batch_length + suffix_length > max_argument_length)
+        {
+            batches.push(batch);
+            batch = Vec::new();
+        }
+        batch_length = if batch.is_empty() {
+            snapshot.to_string().len()
+        } else {
+            batch_length + suffix_length
+        };
+        batch.push(snapshot);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Returns the longest list of snapshots that is given to zfs destroy at
+/// once, based on the system's ARG_MAX. Half of it is left for the
+/// environment, and Linux doesn't accept any single argument longer than
+/// MAX_ARG_STRLEN.
+fn get_default_max_argument_length<T: Communicator>(communicator: &T) -> usize {
+    let arg_max = communicator
+        .get_arg_max()
+        .ok()
+        .and_then(|v| v.trim().parse().ok())
+        .unwrap_or(DEFAULT_ARG_MAX);
+    if cfg!(target_os = "linux") {
+        (arg_max / 2).min(LINUX_MAX_ARGUMENT_LENGTH)
+    } else {
+        arg_max / 2
+    }
+}
+
+/// Returns the batches in the order they are destroyed in. Either all the
+/// batches of each dataset in turn, or the snapshots of the whole pool from
+/// oldest to newest, so that an interrupted run has always freed the oldest
+/// snapshots. For the latter, a batch also ends where the next oldest
+/// snapshot is in another dataset, so snapshots taken at the same time in
+/// several datasets are destroyed one at a time.
+fn get_ordered_batches<'a>(
+    snapshots: &'a [Snapshot],
+    config: &DestroyConfig,
+) -> Vec<(&'a String, Vec<&'a Snapshot>)> {
+    if config.deletion_order() == &DeletionOrder::Dataset {
+        return get_batches(
+            snapshots,
+            config.iteration_count(),
+            config.max_argument_length(),
+        )
+        .into_iter()
+        .flat_map(|(_, dataset_batches)| dataset_batches)
+        .map(|batch| (batch[0].dataset(), batch))
+        .collect();
+    }
+
+    let mut sorted_snapshots: Vec<&Snapshot> = snapshots.iter().collect();
+    sorted_snapshots.sort_by(|a, b| {
+        (a.date(), a.dataset(), a.suffix()).cmp(&(b.date(), b.dataset(), b.suffix()))
+    });
+    let mut ordered_batches = Vec::new();
+    let mut start = 0;
+    while start < sorted_snapshots.len() {
+        let dataset = sorted_snapshots[start].dataset();
+        let end = sorted_snapshots[start..]
+            .iter()
+            .position(|snapshot| snapshot.dataset() != dataset)
+            .map_or(sorted_snapshots.len(), |i| start + i);
+        for batch in split_into_batches(
+            &sorted_snapshots[start..end],
+            config.iteration_count(),
+            config.max_argument_length(),
+        ) {
+            ordered_batches.push((dataset, batch));
+        }
+        start = end;
+    }
+    ordered_batches
+}
+
+fn get_datasets(snapshots: &[Snapshot]) -> BTreeSet<String> {
+    let mut datasets = BTreeSet::new();
+
+    for snapshot in snapshots {
+        if !datasets.contains(snapshot.dataset()) {
+            datasets.insert(snapshot.dataset().clone());
+        }
+    }
+
+    datasets
+}
+
+fn get_cutoff_date(time: DateTime<Local>) -> DateTime<Local> {
+    const DEFAULT_CUTOFF: i64 = 30;
+    time - Duration::days(DEFAULT_CUTOFF)
+}
+
+/// Returns the reason if deleting this many of the pool's snapshots is more
+/// than the configured limits allow.
+fn get_mass_deletion(amount: usize, total: usize, config: &Config) -> Option<String> {
+    if amount == 0 {
+        return None;
+    }
+    let percentage = calculate_percentage(amount as u32, total as u32);
+    match (config.max_delete_percentage(), config.max_delete_count()) {
+        (Some(limit), _) if percentage > limit => Some(format!(
+            "This would delete {} of the pool's {} snapshots ({:.2}%), which is more than {}%.",
+            amount, total, percentage, limit
+        )),
+        (_, Some(limit)) if amount > limit => Some(format!(
+            "This would delete {} snapshots, which is more than {}.",
+            amount, limit
+        )),
+        _ => None,
+    }
+}
+
+/// Calculates the percentage complete
+fn calculate_percentage(numerator: u32, denominator: u32) -> f32 {
+    numerator as f32 / denominator as f32 * 100.0
+}
+
+/// Destroys the ZFS snapshots.
+///
+/// For faster deletions, zfs will be sent a list of snapshots in zfs' desired
+/// format in order to send a bigger batch to zfs at a time.
+///
+/// Example: zfs destroy <dataset>@<label1>,<label2>,<label3>
+///
+/// When the creation order of the snapshots is given, runs of snapshots are
+/// sent as ranges instead.
+///
+/// Example: zfs destroy <dataset>@<label1>%<label3>
+///
+/// With more than one job, several datasets are cleaned at once.
+fn destroy_snapshots<'a, T: Communicator>(
+    communicator: &T,
+    snapshots: &'a [Snapshot],
+    order: Option<&SnapshotOrder>,
+    config: &DestroyConfig,
+) -> Vec<&'a Snapshot> {
+    let mut total_processed: u32 = 0;
+    let snapshot_count = snapshots.len() as u32;
+    let mut deleted_snapshots: Vec<&Snapshot> = Vec::new();
+
+    if config.jobs() > 1 {
+        let mut batches = get_batches(
+            snapshots,
+            config.iteration_count(),
+            config.max_argument_length(),
+        );
+        if config.deletion_order() == &DeletionOrder::Oldest {
+            // The datasets with the oldest snapshots are started first.
+            batches.sort_by_key(|(_, dataset_batches)| *dataset_batches[0][0].date());
+        }
+        let deleted_snapshots =
+            destroy_in_parallel(communicator, batches, order, snapshot_count, config);
+        print_remaining_snapshots(snapshots, &deleted_snapshots);
+        return deleted_snapshots;
+    }
+
+    let start = Instant::now();
+    let mut current_dataset: Option<&String> = None;
+    for (dataset, batch) in get_ordered_batches(snapshots, config) {
+        let budget = match get_remaining_budget(config, start, deleted_snapshots.len()) {
+            Some(budget) => budget,
+            None => break,
+        };
+        let batch = &batch[..batch.len().min(budget)];
+        if current_dataset != Some(dataset) {
+            if current_dataset.is_some() {
+                println!();
+            }
+            println!("Cleaning snapshots for {} ...\n", dataset);
+            current_dataset = Some(dataset);
+        }
+        total_processed += batch.len() as u32;
+        build_and_destroy(
+            communicator,
+            batch,
+            order.and_then(|order| order.get(dataset)),
+            total_processed,
+            snapshot_count,
+        );
+        deleted_snapshots.extend(batch);
+    }
+    if current_dataset.is_some() {
+        println!();
+    }
+    print_remaining_snapshots(snapshots, &deleted_snapshots);
+    deleted_snapshots
+}
+
+/// Returns how many more snapshots may be deleted in this run, or None once
+/// its runtime or deletion budget is used up.
+fn get_remaining_budget(config: &DestroyConfig, start: Instant, deleted: usize) -> Option<usize> {
+    if let Some(max_runtime) = config.max_runtime() {
+        if start.elapsed() >= max_runtime {
+            return None;
+        }
+    }
+    match config.max_deletions() {
+        Some(max_deletions) if deleted >= max_deletions => None,
+        Some(max_deletions) => Some(max_deletions - deleted),
+        None => Some(usize::MAX),
+    }
+}
+
+/// Prints how many snapshots of each dataset are left when the run stopped
+/// early because of its budget.
+fn print_remaining_snapshots(snapshots: &[Snapshot], deleted_snapshots: &[&Snapshot]) {
+    if deleted_snapshots.len() == snapshots.len() {
+        return;
+    }
+    let deleted: HashSet<String> = deleted_snapshots.iter().map(|s| s.to_string()).collect();
+    let mut remaining: BTreeMap<&String, usize> = BTreeMap::new();
+    for snapshot in snapshots
+        .iter()
+        .filter(|snapshot| !deleted.contains(&snapshot.to_string()))
+    {
+        *remaining.entry(snapshot.dataset()).or_insert(0) += 1;
+    }
+    println!(
+        "[Warning] The budget of this run is used up. {} snapshots remain, run Honeydew again later to delete them.\n",
+        snapshots.len() - deleted_snapshots.len()
+    );
+    println!("Remaining Snapshots (By Dataset):");
+    println!("----------------");
+    for (dataset, amount) in remaining {
+        println!("{}: {}", dataset, amount);
+    }
+    println!();
+}
+
+/// Destroys the batches of up to the amount of jobs datasets at once. The
+/// batches of each dataset are still destroyed one after the other. Since
+/// the datasets finish in any order, a summary sorted by dataset is printed
+/// at the end.
+fn destroy_in_parallel<'a, T: Communicator>(
+    communicator: &T,
+    batches: Vec<(String, Vec<Vec<&'a Snapshot>>)>,
+    order: Option<&SnapshotOrder>,
+    snapshot_count: u32,
+    config: &DestroyConfig,
+) -> Vec<&'a Snapshot> {
+    let jobs = config.jobs();
+    println!(
+        "Cleaning snapshots for {} datasets with {} jobs ...\n",
+        batches.len(),
+        jobs
+    );
+    let next_dataset = AtomicUsize::new(0);
+    let start = Instant::now();
+    // The snapshots a job is about to delete are counted against the budget
+    // before it starts, so that the jobs together can't go over it.
+    let reserved = Mutex::new(0);
+    let deleted_snapshots: Mutex<Vec<&Snapshot>> = Mutex::new(Vec::new());
+
+    thread::scope(|scope| {
+        for _ in 0..jobs.min(batches.len()) {
+            scope.spawn(|| {
+                while let Some((dataset, dataset_batches)) =
+                    batches.get(next_dataset.fetch_add(1, Ordering::SeqCst))
+                {
+                    let dataset_order = order.and_then(|order| order.get(dataset));
+                    for batch in dataset_batches {
+                        let batch = {
+                            let mut reserved = reserved.lock().unwrap();
+                            match get_remaining_budget(config, start, *reserved) {
+                                Some(budget) => {
+                                    let batch = &batch[..batch.len().min(budget)];
+                                    *reserved += batch.len();
+                                    batch
+                                }
+                                None => return,
+                            }
+                        };
+                        let list = build_list_to_delete(batch, dataset_order);
+                        if let Err(e) = communicator.destroy_snapshots(list) {
+                            panic!("{:?}", e);
+                        }
+
+                        // The lock is held while printing so that the lines
+                        // of the jobs don't get mixed up.
+                        let mut deleted_snapshots = deleted_snapshots.lock().unwrap();
+                        deleted_snapshots.extend(batch);
+                        let total_processed = deleted_snapshots.len() as u32;
+                        println!(
+                            "Deleted | {:6.2}% <=> [{}/{}] {}",
+                            calculate_percentage(total_processed, snapshot_count),
+                            total_processed,
+                            snapshot_count,
+                            dataset,
+                        );
+                    }
+                }
+            });
+        }
+    });
+
+    let deleted_snapshots = deleted_snapshots.into_inner().unwrap();
+    let mut summary: Vec<(&String, usize)> = batches
+        .iter()
+        .map(|(dataset, _)| {
+            let amount = deleted_snapshots
+                .iter()
+                .filter(|snapshot| snapshot.dataset() == dataset)
+                .count();
+            (dataset, amount)
+        })
+        .collect();
+    summary.sort();
+    println!();
+    println!("Deleted Snapshots (By Dataset):");
+    println!("----------------");
+    for (dataset, amount) in summary {
+        println!("{}: {}", dataset, amount);
+    }
+    println!();
+
+    deleted_snapshots
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::utility;
+
+    #[test]
+    fn get_stale_snapshots_test() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/tmp", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/poudriere", "2020-08-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-09-13-2354-09", "CHECKPOINT"),
+        ];
+
+        let config = utility::get_fake_config("tank", "2020-09-10-0000-00", "");
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank/tmp", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/poudriere", "2020-08-13-2354-09", "CHECKPOINT"),
+        ];
+
+        let stale_snapshots = get_stale_snapshots(snapshots, &config, &utility::get_fake_now());
+
+        assert_eq!(expected_snapshots, stale_snapshots);
+    }
+
+    #[test]
+    fn get_stale_snapshots_should_include_expired() {
+        let mut expired = utility::create_snapshot("tank/tmp", "2020-08-13-2354-09", "KEEP7D");
+        expired.set_expiry(Some(Local.ymd(2020, 8, 20).and_hms(23, 54, 9)));
+        let mut unexpired = utility::create_snapshot("tank/tmp", "2020-08-20-2354-09", "KEEP7D");
+        unexpired.set_expiry(Some(Local.ymd(2020, 8, 27).and_hms(23, 54, 9)));
+        let snapshots = vec![
+            expired.clone(),
+            unexpired,
+            utility::create_snapshot("tank/tmp", "2020-06-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/tmp", "2020-08-13-2354-09", "CHECKPOINT"),
+        ];
+
+        let config = utility::get_fake_config("tank", "2020-07-01-0000-00", "");
+
+        let expected_snapshots = vec![
+            expired,
+            utility::create_snapshot("tank/tmp", "2020-06-13-2354-09", "CHECKPOINT"),
+        ];
+
+        assert_eq!(
+            expected_snapshots,
+            get_stale_snapshots(snapshots, &config, &utility::get_fake_now())
+        );
+    }
+
+    #[test]
+    fn get_stale_snapshots_should_use_max_age_for_label() {
+        let now = Local::now();
+        let date = |days: i64| {
+            (now - Duration::days(days))
+                .format(SNAPSHOT_FORMAT)
+                .to_string()
+        };
+        let snapshots = vec![
+            utility::create_snapshot("tank", &date(3), "HOURLY"),
+            utility::create_snapshot("tank", &date(1), "HOURLY"),
+            utility::create_snapshot("tank", &date(31), "DAILY"),
+            utility::create_snapshot("tank", &date(29), "DAILY"),
+            utility::create_snapshot("tank", &date(200), "MONTHLY"),
+            utility::create_snapshot("tank", &date(45), "CHECKPOINT"),
+            utility::create_snapshot("tank", &date(35), "CHECKPOINT"),
+        ];
+        let config = Config::new(
+            &utility::FakeCommunicator::new(true),
+            Options {
+                pool: "tank".to_string(),
+                date: (now - Duration::days(40))
+                    .format(SNAPSHOT_FORMAT)
+                    .to_string(),
+                max_age: "hourly=2d,DAILY=30d,MONTHLY=1y".to_string(),
+                ignore_case: true,
+                ..Default::default()
+            },
+        );
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank", &date(3), "HOURLY"),
+            utility::create_snapshot("tank", &date(31), "DAILY"),
+            utility::create_snapshot("tank", &date(45), "CHECKPOINT"),
+        ];
+        let stale_snapshots = get_stale_snapshots(snapshots, &config, &now);
+        assert_eq!(expected_snapshots, stale_snapshots);
+
+        assert_eq!(
+            vec![
+                ("hourly=2d".to_string(), 1),
+                ("DAILY=30d".to_string(), 1),
+                ("MONTHLY=1y".to_string(), 0),
+                ("Cut Off Date".to_string(), 1),
+            ],
+            count_by_max_age(&stale_snapshots, &config)
+        );
+    }
+
+    #[test]
+    fn get_stale_snapshots_should_measure_age_against_anchor() {
+        let now = Local::now();
+        let date = |days: i64| {
+            (now - Duration::days(days))
+                .format(SNAPSHOT_FORMAT)
+                .to_string()
+        };
+        let snapshots = vec![
+            utility::create_snapshot("backup/os", &date(130), "DAILY"),
+            utility::create_snapshot("backup/os", &date(100), "DAILY"),
+            utility::create_snapshot("backup/os", &date(90), "DAILY"),
+            utility::create_snapshot("backup/home", &date(130), "DAILY"),
+            utility::create_snapshot("backup/home", &date(110), "DAILY"),
+        ];
+        let get_config = |date: &str, cutoff_anchor: CutoffAnchor| {
+            Config::new(
+                &utility::FakeCommunicator::new(true),
+                Options {
+                    pool: "backup".to_string(),
+                    date: date.to_string(),
+                    cutoff_anchor,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let config = get_config("", CutoffAnchor::Now);
+        assert_eq!(
+            5,
+            get_stale_snapshots(snapshots.clone(), &config, &now).len()
+        );
+
+        let config = get_config("", CutoffAnchor::Dataset);
+        assert_eq!(
+            vec![utility::create_snapshot("backup/os", &date(130), "DAILY")],
+            get_stale_snapshots(snapshots.clone(), &config, &now)
+        );
+
+        let config = get_config("", CutoffAnchor::Pool);
+        assert_eq!(
+            vec![
+                utility::create_snapshot("backup/os", &date(130), "DAILY"),
+                utility::create_snapshot("backup/home", &date(130), "DAILY"),
+            ],
+            get_stale_snapshots(snapshots.clone(), &config, &now)
+        );
+
+        // A cutoff date that was given explicitly isn't moved.
+        let config = get_config(&date(105), CutoffAnchor::Dataset);
+        assert_eq!(
+            vec![
+                utility::create_snapshot("backup/os", &date(130), "DAILY"),
+                utility::create_snapshot("backup/home", &date(130), "DAILY"),
+                utility::create_snapshot("backup/home", &date(110), "DAILY"),
+            ],
+            get_stale_snapshots(snapshots, &config, &now)
+        );
+    }
+
+    #[test]
+    fn get_stale_snapshots_should_not_anchor_to_the_future() {
+        let now = Local::now();
+        let date = |days: i64| {
+            (now - Duration::days(days))
+                .format(SNAPSHOT_FORMAT)
+                .to_string()
+        };
+        let snapshots = vec![
+            utility::create_snapshot("backup/os", &date(40), "DAILY"),
+            utility::create_snapshot("backup/os", &date(10), "DAILY"),
+            utility::create_snapshot("backup/os", &date(-365), "DAILY"),
+        ];
+        let get_config = |cutoff_anchor: CutoffAnchor| {
+            Config::new(
+                &utility::FakeCommunicator::new(true),
+                Options {
+                    pool: "backup".to_string(),
+                    cutoff_anchor,
+                    ..Default::default()
+                },
+            )
+        };
+
+        let expected_snapshots = vec![utility::create_snapshot("backup/os", &date(40), "DAILY")];
+        for cutoff_anchor in [CutoffAnchor::Now, CutoffAnchor::Dataset, CutoffAnchor::Pool] {
+            let config = get_config(cutoff_anchor);
+            assert_eq!(
+                expected_snapshots,
+                get_stale_snapshots(snapshots.clone(), &config, &now)
+            );
+            assert!(
+                config.anchored_cutoff_date_for("DAILY", &(now + Duration::days(365)))
+                    <= *config.cutoff_date_for("DAILY")
+            );
+        }
+    }
+
+    #[test]
+    fn get_config_entries_test() {
+        let entries =
+            get_config_entries("# Honeydew\n\nmax-age   HOURLY=2d \n  max-age DAILY=30d\n");
+        assert_eq!(
+            vec![
+                ("max-age".to_string(), "HOURLY=2d".to_string()),
+                ("max-age".to_string(), "DAILY=30d".to_string()),
+            ],
+            entries
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Unknown option in the config file (Line 2): colour")]
+    fn get_config_entries_should_panic_for_unknown_options() {
+        get_config_entries("max-age HOURLY=2d\ncolour blue\n");
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing value in the config file (Line 1): max-age")]
+    fn get_config_entries_should_panic_for_missing_values() {
+        get_config_entries("max-age\n");
+    }
+
+    #[test]
+    fn get_listed_snapshots_should_read_expiry() {
+        let unparsed_snapshots = vec![
+            "tank@2020-08-13-2354-09-CHECKPOINT\t1597377249\t-\t2020-08-20".to_string(),
+            "tank@2020-08-13-2354-09-DAILY\t1597377249\t-\t2d".to_string(),
+            "tank@2020-08-13-2354-09-KEEP7D\t1597377249\t-\t-".to_string(),
+            "tank@2020-08-13-2354-09-KEEP7D\t1597377249\t-\t1w".to_string(),
+            "tank@2020-08-13-2354-09-WEEKLY\t1597377249\t-\tsoon".to_string(),
+            "tank@2020-08-13-2354-09-WEEKLY\t1597377249\t-\t-".to_string(),
+        ];
+        let creation = Local.timestamp(1597377249, 0);
+
+        let get_expiries = |label_expiry| {
+            get_listed_snapshots(
+                unparsed_snapshots.clone(),
+                &TimestampSource::Name,
+                DEFAULT_CREATION_TOLERANCE,
+                label_expiry,
+            )
+            .iter()
+            .map(|snapshot| snapshot.expiry())
+            .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            vec![
+                Some(Local.ymd(2020, 8, 20).and_hms(0, 0, 0)),
+                Some(creation + Duration::days(2)),
+                Some(creation + Duration::days(7)),
+                Some(creation + Duration::weeks(1)),
+                None,
+                None,
+            ],
+            get_expiries(true)
+        );
+        // The label is only read with label expiry turned on.
+        assert_eq!(None, get_expiries(false)[2]);
+    }
+
+    #[test]
+    fn parse_lifetime_test() {
+        assert_eq!(Some(Duration::hours(12)), parse_lifetime("12h"));
+        assert_eq!(Some(Duration::days(7)), parse_lifetime("7D"));
+        assert_eq!(Some(Duration::weeks(2)), parse_lifetime("2w"));
+        assert!(parse_lifetime("6m").is_none());
+        assert_eq!(Some(Duration::days(365)), parse_lifetime("1y"));
+        assert!(parse_lifetime("7").is_none());
+        assert!(parse_lifetime("d").is_none());
+        assert!(parse_lifetime("-7d").is_none());
+        assert!(parse_lifetime("99999999y").is_none());
+        assert!(parse_label_lifetime("CHECKPOINT").is_none());
+        assert_eq!(Some(Duration::days(30)), parse_label_lifetime("keep30d"));
+    }
+
+    #[test]
+    fn parse_snapshot_should_return_none() {
+        let snapshot = "boot@lol";
+
+        let result = parse_snapshot(snapshot);
+
+        assert_eq!(None, result);
+    }
+
+    #[test]
+    fn parse_snapshot_should_return_trashed_snapshots() {
+        let name = "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17";
+
+        let snapshot = parse_snapshot(name).unwrap();
+
+        assert!(snapshot.is_trashed());
+        assert_eq!(Some(utility::get_fake_now()), snapshot.trashed());
+        assert_eq!("CHECKPOINT", snapshot.label());
+        assert_eq!(&Local.ymd(2020, 7, 13).and_hms(23, 54, 9), snapshot.date());
+        assert_eq!(name, snapshot.to_string());
+        assert!(!parse_snapshot("tank@2020-07-13-2354-09-CHECKPOINT")
+            .unwrap()
+            .is_trashed());
+        assert_eq!(
+            None,
+            parse_snapshot("tank@2020-07-13-2354-09-CHECKPOINT-TRASH-soon")
+        );
+    }
+
+    #[test]
+    fn trash_snapshots_should_rename_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot_from_string("tank@2020-07-13-2354-09-CHECKPOINT"),
+            utility::create_snapshot_from_string("tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT"),
+        ];
+
+        let trashed_snapshots = trash_snapshots(
+            &communicator,
+            &snapshots,
+            utility::get_fake_now(),
+            &utility::get_fake_destroy_config(None),
+        );
+
+        assert_eq!(
+            vec![
+                "tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17",
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17",
+            ],
+            trashed_snapshots
+        );
+        assert_eq!(
+            vec![
+                "tank@2020-07-13-2354-09-CHECKPOINT tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17",
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17",
+            ],
+            communicator.renamed_snapshots()
+        );
+    }
+
+    #[test]
+    fn trash_snapshots_should_stop_at_max_deletions() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot_from_string("tank@2020-07-13-2354-09-CHECKPOINT"),
+            utility::create_snapshot_from_string("tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT"),
+        ];
+
+        let trashed_snapshots = trash_snapshots(
+            &communicator,
+            &snapshots,
+            utility::get_fake_now(),
+            &utility::get_fake_destroy_config(Some(1)),
+        );
+
+        assert_eq!(
+            vec!["tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17"],
+            trashed_snapshots
+        );
+        assert_eq!(1, communicator.renamed_snapshots().len());
+    }
+
+    #[test]
+    fn get_purgeable_snapshots_should_respect_grace_period() {
+        let creation = Local.ymd(2020, 7, 13).and_hms(23, 54, 9).timestamp();
+        let snapshots: Vec<String> = [
+            "tank@2020-07-13-2354-09-CHECKPOINT",
+            "tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-10-1200-00",
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-20-1200-00",
+            "tank/gentoo/os@manual-TRASH-2020-08-01-1200-00",
+            "boot@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-10-1200-00",
+        ]
+        .iter()
+        .map(|name| format!("{}\t{}", name, creation))
+        .collect();
+
+        let trashed_snapshots = get_trashed_snapshots(snapshots, "tank");
+        assert_eq!(3, trashed_snapshots.len());
+
+        let purgeable_snapshots = get_purgeable_snapshots(
+            trashed_snapshots,
+            utility::get_fake_now() - Duration::days(7),
+        );
+        let names: Vec<String> = purgeable_snapshots.iter().map(|s| s.to_string()).collect();
+        assert_eq!(
+            vec![
+                "tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-10-1200-00",
+                "tank/gentoo/os@manual-TRASH-2020-08-01-1200-00",
+            ],
+            names
+        );
+    }
+
+    #[test]
+    fn get_snapshots_for_should_skip_trashed_snapshots() {
+        let snapshots = vec![
+            parse_snapshot("tank@2020-07-13-2354-09-CHECKPOINT").unwrap(),
+            parse_snapshot("tank@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-10-1200-00").unwrap(),
+        ];
+
+        let result = get_snapshots_for("tank", &[], &[], false, snapshots);
+
+        assert_eq!(1, result.len());
+        assert!(!result[0].is_trashed());
+    }
+
+    #[test]
+    fn get_listed_snapshots_should_return_snapshots() {
+        let snapshots: Vec<String> = [
+            "elephants/in/space@2022-12-31-0000-00-CHECKPOINT",
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "tank/tmp@2020-08-12-1237-49-CHECKPOINT",
+            "tank/poudriere@2020-08-12-1237-49-CHECKPOINT",
+            "tank/os/main@2020-08-12-1237-49-CHECKPOINT",
+            "tank/os/main@2022-10-09-1234-60-CHECKPOINT",
+        ]
+        .iter()
+        .map(|&name| String::from(name))
+        .collect();
+
+        let expected_snapshots: Vec<Snapshot> = snapshots
+            .iter()
+            .map(|snapshot| utility::create_snapshot_from_string(snapshot))
+            .collect();
+
+        let result_snapshots =
+            get_listed_snapshots(snapshots.clone(), &TimestampSource::Name, 60, false);
+
+        assert_eq!(result_snapshots.len(), 7);
+        assert_eq!(result_snapshots, expected_snapshots);
+    }
+
+    #[test]
+    fn get_listed_snapshots_should_ignore_creation_for_names() {
+        let snapshots = vec![
+            "tank/os@2020-08-12-1237-49-CHECKPOINT\t1500000000".to_string(),
+            "tank/os@manual\t1500000000".to_string(),
+        ];
+
+        let expected_snapshots = vec![utility::create_snapshot(
+            "tank/os",
+            "2020-08-12-1237-49",
+            "CHECKPOINT",
+        )];
+
+        assert_eq!(
+            expected_snapshots,
+            get_listed_snapshots(snapshots, &TimestampSource::Name, 60, false)
+        );
+    }
+
+    #[test]
+    fn get_listed_snapshots_should_use_creation() {
+        let creation = Local.ymd(2020, 8, 12).and_hms(12, 37, 49);
+        let snapshots = vec![
+            format!(
+                "tank/os@2020-08-12-1237-49-CHECKPOINT\t{}",
+                creation.timestamp()
+            ),
+            format!("tank/os@manual\t{}", creation.timestamp()),
+            "tank/os@no-creation".to_string(),
+        ];
+
+        let result_snapshots =
+            get_listed_snapshots(snapshots, &TimestampSource::Creation, 60, false);
+
+        assert_eq!(result_snapshots.len(), 2);
+        assert_eq!(
+            result_snapshots[0].to_string(),
+            "tank/os@2020-08-12-1237-49-CHECKPOINT"
+        );
+        assert_eq!(result_snapshots[0].label(), "CHECKPOINT");
+        assert_eq!(result_snapshots[1].to_string(), "tank/os@manual");
+        assert_eq!(result_snapshots[1].label(), "");
+        assert_eq!(result_snapshots[1].date(), &creation);
+    }
+
+    #[test]
+    fn get_listed_snapshots_should_read_protection() {
+        let snapshots = vec![
+            "tank/os@2020-08-12-1237-49-CHECKPOINT\t1597250269\ton".to_string(),
+            "tank/os@2020-08-13-1237-49-CHECKPOINT\t1597336669\t-".to_string(),
+            "tank/os@2020-08-14-1237-49-CHECKPOINT".to_string(),
+        ];
+
+        let result_snapshots = get_listed_snapshots(snapshots, &TimestampSource::Name, 60, false);
+
+        assert!(result_snapshots[0].is_protected());
+        assert!(!result_snapshots[1].is_protected());
+        assert!(!result_snapshots[2].is_protected());
+    }
+
+    #[test]
+    fn get_snapshots_for_should_filter_correctly() {
+        let initial_snapshots = vec![
+            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot(
+                "backup/tank/gentoo/home",
+                "2020-07-13-2354-09",
+                "CHECKPOINT",
+            ),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "LOL"),
+        ];
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "CHECKPOINT"),
+        ];
+
+        assert_eq!(
+            expected_snapshots,
+            get_snapshots_for(
+                "tank",
+                &["CHECKPOINT".to_string()],
+                &[],
+                false,
+                initial_snapshots
+            )
+        );
+    }
+
+    #[test]
+    fn get_snapshots_for_should_match_label_patterns() {
+        let initial_snapshots = vec![
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "HOURLY"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "daily"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "MONTHLY"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREUPGRADE"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREBOOT"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "CHECKPOINT"),
+        ];
+
+        let labels = split_labels("hourly, DAILY,PRE*");
+        let excluded_labels = split_labels("*boot");
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "HOURLY"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "daily"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREUPGRADE"),
+        ];
+        assert_eq!(
+            expected_snapshots,
+            get_snapshots_for(
+                "tank",
+                &labels,
+                &excluded_labels,
+                true,
+                initial_snapshots.clone()
+            )
+        );
+
+        // Without ignoring case, the labels must match exactly.
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREUPGRADE"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREBOOT"),
+        ];
+        assert_eq!(
+            expected_snapshots,
+            get_snapshots_for(
+                "tank",
+                &labels,
+                &excluded_labels,
+                false,
+                initial_snapshots.clone()
+            )
+        );
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "HOURLY"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "daily"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "PREUPGRADE"),
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "CHECKPOINT"),
+        ];
+        assert_eq!(
+            expected_snapshots,
+            get_snapshots_for(
+                "tank",
+                &[],
+                &split_labels("monthly,PREBOOT"),
+                true,
+                initial_snapshots
+            )
+        );
+    }
+
+    #[test]
+    fn filter_datasets_should_count_each_rule() {
+        let snapshots = vec![
+            utility::create_snapshot("tank", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/poudriere/jails", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/poudriere/ports", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/var/docker/abc", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot(
+                "tank/poudriere/docker/abc",
+                "2020-08-12-1237-49",
+                "CHECKPOINT",
+            ),
+        ];
+
+        let (remaining_snapshots, rules) = filter_datasets(
+            snapshots.clone(),
+            &["tank/*".to_string()],
+            &["tank/poudriere/*".to_string(), "*/docker/*".to_string()],
+        );
+        assert_eq!(
+            vec![utility::create_snapshot(
+                "tank/gentoo/os",
+                "2020-08-12-1237-49",
+                "CHECKPOINT"
+            )],
+            remaining_snapshots
+        );
+        assert_eq!(
+            vec![
+                ("include-dataset tank/*".to_string(), 1),
+                ("exclude-dataset tank/poudriere/*".to_string(), 3),
+                ("exclude-dataset */docker/*".to_string(), 1),
+            ],
+            rules
+        );
+
+        let (remaining_snapshots, rules) = filter_datasets(snapshots.clone(), &[], &[]);
+        assert_eq!(snapshots, remaining_snapshots);
+        assert!(rules.is_empty());
+    }
+
+    #[test]
+    fn all_snapshots_should_be_retrieved() {
+        let expected_snapshots = vec![
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "backup/tank/gentoo/home@2020-07-13-2354-09-CHECKPOINT",
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+            "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT",
+        ];
+
+        assert_eq!(
+            expected_snapshots,
+            get_snapshots(&utility::FakeCommunicator::new(true), &[])
+        );
+    }
+
+    #[test]
+    fn get_excluded_snapshots_test() {
+        let expected_snapshots = vec![utility::create_snapshot(
+            "boot",
+            "2020-08-12-1237-49",
+            "CHECKPOINT",
+        )];
+
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_config("boot", "2020-05-01-1200-00", "");
+        let snapshots = get_snapshots_for(
+            config.pool(),
+            config.labels(),
+            config.excluded_labels(),
+            config.ignore_case(),
+            get_parsed_snapshots(&communicator, &config),
+        );
+        let exclusions = get_exclusions(&get_exclude_lines(&communicator, config.exclude_file()));
+
+        assert_eq!(
+            expected_snapshots,
+            get_excluded_snapshots(&snapshots, &exclusions, utility::get_fake_today())
+        );
+    }
+
+    #[test]
+    fn get_relevant_snapshots_test() {
+        pub struct FakeCommunicator;
+        impl Communicator for FakeCommunicator {
+            fn get_snapshots(&self, _properties: &[&str]) -> SystemResult {
+                Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
+                    tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n\
+                    tank/gentoo/os@2020-05-01-1100-00-CHECKPOINT\n\
+                    tank/gentoo/home@2020-04-25-1300-15-CHECKPOINT\n\
+                    tank@2020-01-01-2354-09-CHECKPOINT\n"
+                    .to_string())
+            }
+            fn get_excluded_snapshots(&self, _exclude_file: &str) -> SystemResult {
+                Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
+                tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n"
+                    .to_string())
+            }
+        }
+
+        let exclusions = utility::create_exclusions(&[
+            "tank/gentoo/home@2020-04-25-1300-15-CHECKPOINT", // older but excluded
+        ]);
+        let expected_snapshots = vec![utility::create_snapshot(
+            "tank/gentoo/os",
+            "2020-05-01-1100-00",
+            "CHECKPOINT",
+        )];
+        // The only snapshot of its dataset.
+        let expected_newest_snapshots = vec![utility::create_snapshot(
+            "tank",
+            "2020-01-01-2354-09",
+            "CHECKPOINT",
+        )];
+        let config = utility::get_fake_config("tank", "2020-05-01-1200-00", "");
+        let (relevant_snapshots, newest_snapshots) = get_relevant_snapshots(
+            get_snapshots_for(
+                config.pool(),
+                config.labels(),
+                config.excluded_labels(),
+                config.ignore_case(),
+                get_parsed_snapshots(&FakeCommunicator, &config),
+            ),
+            &config,
+            &exclusions,
+            utility::get_fake_now(),
+        );
+        assert_eq!(expected_snapshots, relevant_snapshots);
+        assert_eq!(expected_newest_snapshots, newest_snapshots);
+    }
+
+    #[test]
+    fn get_newest_snapshots_should_group_by_dataset_and_label() {
+        let snapshots = vec![
+            utility::create_snapshot("tank", "2020-01-01-2354-09", "DAILY"),
+            utility::create_snapshot("tank", "2020-01-03-2354-09", "DAILY"),
+            utility::create_snapshot("tank", "2020-01-02-2354-09", "DAILY"),
+            utility::create_snapshot("tank", "2020-01-01-2354-09", "HOURLY"),
+            utility::create_snapshot("tank/os", "2020-01-01-2354-09", "DAILY"),
+        ];
+
+        let mut newest_snapshots: Vec<String> =
+            get_newest_snapshots(&snapshots, 2).into_iter().collect();
+        newest_snapshots.sort();
+        assert_eq!(
+            vec![
+                "tank/os@2020-01-01-2354-09-DAILY",
+                "tank@2020-01-01-2354-09-HOURLY",
+                "tank@2020-01-02-2354-09-DAILY",
+                "tank@2020-01-03-2354-09-DAILY",
+            ],
+            newest_snapshots
+        );
+        assert!(get_newest_snapshots(&snapshots, 0).is_empty());
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_test() {
+        let snapshots = vec![
+            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/home", "2020-04-25-1300-15", "CHECKPOINT"),
+            utility::create_snapshot("tank", "2020-01-01-2354-09", "CHECKPOINT"),
+        ];
+
+        let exclusions = utility::create_exclusions(&[
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "tank/gentoo/home@2020-04-25-1300-15-CHECKPOINT",
+            "tank@2020-01-01-2354-09-CHECKPOINT",
+        ]);
+
+        let expected_snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
+        ];
+
+        assert_eq!(
+            expected_snapshots,
+            remove_excluded_snapshots(snapshots, &exclusions, utility::get_fake_today())
+        )
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_should_match_patterns() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/vm/bsd", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "RELEASE"),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-14-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/home", "2020-08-13-2354-09", "CHECKPOINT"),
+        ];
+
+        let exclusions = utility::create_exclusions(&[
+            "tank/vm/*@*",
+            "*@*-RELEASE",
+            "tank/gentoo/os@2020-07-*",
+            "re:^tank/gentoo/home@",
+        ]);
+
+        let expected_snapshots = vec![utility::create_snapshot(
+            "tank/gentoo/os",
+            "2020-08-13-2354-09",
+            "CHECKPOINT",
+        )];
+
+        assert_eq!(
+            expected_snapshots,
+            remove_excluded_snapshots(snapshots, &exclusions, utility::get_fake_today())
+        )
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_should_remove_protected() {
+        let mut protected_snapshot =
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT");
+        protected_snapshot.set_protection(Some(Pin::default()));
+        let snapshots = vec![
+            protected_snapshot.clone(),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-14-2354-09", "CHECKPOINT"),
+        ];
+
+        let expected_snapshots = vec![utility::create_snapshot(
+            "tank/gentoo/os",
+            "2020-07-14-2354-09",
+            "CHECKPOINT",
+        )];
+
+        assert_eq!(
+            vec![protected_snapshot],
+            get_excluded_snapshots(&snapshots, &[], utility::get_fake_today())
+        );
+        assert_eq!(
+            expected_snapshots,
+            remove_excluded_snapshots(snapshots, &[], utility::get_fake_today())
+        );
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_should_ignore_expired_pins() {
+        let snapshots = vec![
+            utility::create_snapshot_from_string("tank@2020-01-01-2354-09-CHECKPOINT"),
+            utility::create_snapshot_from_string("tank@2020-02-01-2354-09-CHECKPOINT"),
+            utility::create_snapshot_from_string("tank@2020-03-01-2354-09-CHECKPOINT"),
+        ];
+        let exclusions = utility::create_exclusions(&[
+            "tank@2020-01-01-2354-09-CHECKPOINT until=2020-08-22",
+            "tank@2020-02-01-2354-09-CHECKPOINT until=2020-08-23",
+            "tank@2020-03-01-2354-09-CHECKPOINT until=2020-08-01",
+            "tank@2020-03-* # Kept forever",
+        ]);
+        let today = utility::get_fake_today();
+
+        assert_eq!(
+            vec![utility::create_snapshot_from_string(
+                "tank@2020-01-01-2354-09-CHECKPOINT"
+            )],
+            remove_excluded_snapshots(snapshots.clone(), &exclusions, today)
+        );
+        assert_eq!(
+            vec![utility::create_snapshot_from_string(
+                "tank@2020-01-01-2354-09-CHECKPOINT"
+            )],
+            get_expired_snapshots(&snapshots, &exclusions, today)
+        );
+        assert_eq!(
+            vec![
+                "tank@2020-01-01-2354-09-CHECKPOINT (EXPIRED on 2020-08-22)",
+                "tank@2020-02-01-2354-09-CHECKPOINT (Until 2020-08-23: 1 day(s) left)",
+                "tank@2020-03-01-2354-09-CHECKPOINT",
+            ],
+            snapshots
+                .iter()
+                .map(|snapshot| format_excluded_snapshot(snapshot, &exclusions, today))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_should_ignore_expired_protection() {
+        let mut snapshot =
+            utility::create_snapshot_from_string("tank@2020-01-01-2354-09-CHECKPOINT");
+        snapshot.set_protection(parse_protection("on until=2020-08-01"));
+
+        assert_eq!(
+            vec![snapshot.clone()],
+            remove_excluded_snapshots(vec![snapshot], &[], utility::get_fake_today())
+        );
+    }
+
+    #[test]
+    fn parse_exclusion_should_skip_comments_and_blank_lines() {
+        assert!(parse_exclusion("").unwrap().is_none());
+        assert!(parse_exclusion("   ").unwrap().is_none());
+        assert!(parse_exclusion("# tank@2020-07-13-2354-09-CHECKPOINT")
+            .unwrap()
+            .is_none());
+        assert!(parse_exclusion("re:[").is_err());
+        assert!(parse_exclusion("tank/gentoo/os").is_err());
+        assert!(matches!(
+            parse_exclusion("tank@2020-07-13-2354-09-CHECKPOINT")
+                .unwrap()
+                .unwrap()
+                .pattern(),
+            Pattern::Exact(_)
+        ));
+        assert!(matches!(
+            parse_exclusion("tank@*").unwrap().unwrap().pattern(),
+            Pattern::Glob(_)
+        ));
+        assert!(matches!(
+            parse_exclusion("re:tank").unwrap().unwrap().pattern(),
+            Pattern::Regex(_)
+        ));
+    }
+
+    #[test]
+    fn get_exclude_problems_test() {
+        let exclude_lines: Vec<String> = [
+            "# Comment",
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+            "tank/gentoo/os@2020-07-13-2354-61-CHECKPOINT",
+            "re:(",
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "tank/gentoo/os@2019-*",
+            "*@*-CHECKPOINT",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+
+        let snapshots = vec![
+            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+        ];
+
+        let expected_problems = vec![
+            ExcludeProblem::Unparseable(
+                3,
+                "tank/gentoo/os@2020-07-13-2354-61-CHECKPOINT".to_string(),
+                "Invalid snapshot format or date".to_string(),
+            ),
+            ExcludeProblem::Unparseable(
+                4,
+                "re:(".to_string(),
+                "Invalid regular expression".to_string(),
+            ),
+            ExcludeProblem::OtherPool(5, "boot@2020-08-12-1237-49-CHECKPOINT".to_string()),
+            ExcludeProblem::Unmatched(6, "tank/gentoo/os@2019-*".to_string()),
+        ];
+
+        assert_eq!(
+            expected_problems,
+            get_exclude_problems(
+                &exclude_lines,
+                &utility::get_fake_config("tank", "2020-05-01-1200-00", ""),
+                &snapshots
+            )
+        );
+    }
+
+    #[test]
+    fn glob_matches_test() {
+        assert!(glob_matches(
+            "tank/vm/*@*",
+            "tank/vm/bsd@2020-07-13-2354-09-CHECKPOINT"
+        ));
+        assert!(glob_matches(
+            "*@*-RELEASE",
+            "tank@2020-07-13-2354-09-RELEASE"
+        ));
+        assert!(glob_matches(
+            "tank@2020-07-1?-*",
+            "tank@2020-07-13-2354-09-RELEASE"
+        ));
+        assert!(glob_matches("*", ""));
+        assert!(!glob_matches(
+            "*@*-RELEASE",
+            "tank@2020-07-13-2354-09-CHECKPOINT"
+        ));
+        assert!(!glob_matches(
+            "tank/vm/*@*",
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT"
+        ));
+        assert!(!glob_matches("tank@?", "tank@"));
+    }
+
+    #[test]
+    fn remove_excluded_snapshots_should_compare_names() {
+        let creation = Local.ymd(2021, 1, 1).and_hms(0, 0, 0);
+        let snapshots = vec![Snapshot::from_creation(
+            "tank",
+            "tank/gentoo/os",
+            "2020-07-13-2354-09-CHECKPOINT",
+            "CHECKPOINT",
+            creation,
+        )];
+
+        let exclusions =
+            utility::create_exclusions(&["tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT"]);
+
+        assert!(
+            remove_excluded_snapshots(snapshots, &exclusions, utility::get_fake_today()).is_empty()
+        );
+    }
+
+    #[test]
+    fn build_list_to_delete_test() {
+        let snapshots = [
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/gentoo/os", "2020-09-05-1300-00", "CHECKPOINT"),
+        ];
+        let references: Vec<&Snapshot> = snapshots.iter().collect();
+        let expected_result = "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT,2020-05-01-1100-00-CHECKPOINT,2020-09-05-1300-00-CHECKPOINT";
+        assert_eq!(expected_result, build_list_to_delete(&references, None));
+    }
+
+    #[test]
+    fn build_list_to_delete_should_use_ranges() {
+        let order = get_snapshot_order(&utility::FakeCommunicator::new(true));
+        let home_order = order.get("tank/home");
+        let snapshots = [
+            utility::create_snapshot("tank/home", "2020-09-05-1340-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1330-00", "CHECKPOINT"),
+        ];
+        let references: Vec<&Snapshot> = snapshots.iter().collect();
+
+        // tank/home@manual isn't queued, so it's never inside a range.
+        assert_eq!(
+            "tank/home@2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT,2020-09-05-1320-00-CHECKPOINT%2020-09-05-1340-00-CHECKPOINT",
+            build_list_to_delete(&references, home_order)
+        );
+
+        // Snapshots that aren't in the order are listed one by one.
+        let other_snapshot =
+            utility::create_snapshot("tank/home", "2020-09-05-1350-00", "CHECKPOINT");
+        let mut references = references;
+        references.push(&other_snapshot);
+        assert_eq!(
+            "tank/home@2020-09-05-1340-00-CHECKPOINT,2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT,2020-09-05-1320-00-CHECKPOINT,2020-09-05-1330-00-CHECKPOINT,2020-09-05-1350-00-CHECKPOINT",
+            build_list_to_delete(&references, home_order)
+        );
+    }
+
+    #[test]
+    fn get_snapshot_sets_should_group_by_topmost_dataset() {
+        let snapshots: Vec<Snapshot> = [
+            "tank@2020-09-05-1300-00-CHECKPOINT",
+            "tank/a@2020-09-05-1300-00-CHECKPOINT",
+            "tank/a/b/c@2020-09-05-1300-00-CHECKPOINT",
+            "tank/d/e@2020-09-05-1310-00-CHECKPOINT",
+            "tank/d/e/f@2020-09-05-1310-00-CHECKPOINT",
+            "tank/g@2020-09-05-1310-00-CHECKPOINT",
+        ]
+        .iter()
+        .map(|name| utility::create_snapshot_from_string(name))
+        .collect();
+
+        let sets: Vec<(String, usize)> = get_snapshot_sets(&snapshots)
+            .into_iter()
+            .map(|(set, members)| (set, members.len()))
+            .collect();
+
+        assert_eq!(
+            vec![
+                ("tank/d/e@2020-09-05-1310-00-CHECKPOINT".to_string(), 2),
+                ("tank/g@2020-09-05-1310-00-CHECKPOINT".to_string(), 1),
+                ("tank@2020-09-05-1300-00-CHECKPOINT".to_string(), 3),
+            ],
+            sets
+        );
+    }
+
+    #[test]
+    fn keep_whole_sets_should_keep_partial_sets() {
+        let pool_snapshots: Vec<Snapshot> = [
+            "tank@2020-09-05-1300-00-CHECKPOINT",
+            "tank/a@2020-09-05-1300-00-CHECKPOINT",
+            "tank/b@2020-09-05-1300-00-CHECKPOINT",
+            "tank@2020-09-05-1310-00-CHECKPOINT",
+            "tank/a@2020-09-05-1310-00-CHECKPOINT",
+        ]
+        .iter()
+        .map(|name| utility::create_snapshot_from_string(name))
+        .collect();
+        // tank/b@2020-09-05-1300-00-CHECKPOINT is excluded.
+        let stale_snapshots = vec![
+            pool_snapshots[0].clone(),
+            pool_snapshots[1].clone(),
+            pool_snapshots[3].clone(),
+            pool_snapshots[4].clone(),
+        ];
+
+        let (stale_snapshots, kept_snapshots) = keep_whole_sets(stale_snapshots, &pool_snapshots);
+
+        assert_eq!(
+            vec![pool_snapshots[3].clone(), pool_snapshots[4].clone()],
+            stale_snapshots
+        );
+        assert_eq!(
+            vec![pool_snapshots[0].clone(), pool_snapshots[1].clone()],
+            kept_snapshots
+        );
+    }
+
+    #[test]
+    fn destroy_sets_should_destroy_recursively() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots: Vec<Snapshot> = [
+            "tank@2020-09-05-1300-00-CHECKPOINT",
+            "tank/a@2020-09-05-1300-00-CHECKPOINT",
+            "tank@2020-09-05-1310-00-CHECKPOINT",
+        ]
+        .iter()
+        .map(|name| utility::create_snapshot_from_string(name))
+        .collect();
+
+        let deleted_snapshots = destroy_sets(
+            &communicator,
+            &snapshots,
+            &utility::get_fake_destroy_config(None),
+        );
+
+        assert_eq!(3, deleted_snapshots.len());
+        assert_eq!(
+            vec![
+                "tank@2020-09-05-1300-00-CHECKPOINT",
+                "tank@2020-09-05-1310-00-CHECKPOINT",
+            ],
+            communicator.destroyed_recursively()
+        );
+        assert!(build_set_script("tank", &snapshots, utility::get_fake_now()).ends_with(
+            "zfs list -H -o name -t snapshot 'tank@2020-09-05-1310-00-CHECKPOINT' > /dev/null\n\
+            zfs destroy -r 'tank@2020-09-05-1310-00-CHECKPOINT'\n"
+        ));
+    }
+
+    #[test]
+    fn destroy_sets_should_stop_before_a_set_over_max_deletions() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots: Vec<Snapshot> = [
+            "tank@2020-09-05-1300-00-CHECKPOINT",
+            "tank/a@2020-09-05-1300-00-CHECKPOINT",
+            "tank@2020-09-05-1310-00-CHECKPOINT",
+        ]
+        .iter()
+        .map(|name| utility::create_snapshot_from_string(name))
+        .collect();
+
+        // The first set has two snapshots, so it doesn't fit.
+        let deleted_snapshots = destroy_sets(
+            &communicator,
+            &snapshots,
+            &utility::get_fake_destroy_config(Some(1)),
+        );
+        assert!(deleted_snapshots.is_empty());
+        assert!(communicator.destroyed_recursively().is_empty());
+
+        let deleted_snapshots = destroy_sets(
+            &communicator,
+            &snapshots,
+            &utility::get_fake_destroy_config(Some(2)),
+        );
+        assert_eq!(2, deleted_snapshots.len());
+        assert_eq!(
+            vec!["tank@2020-09-05-1300-00-CHECKPOINT"],
+            communicator.destroyed_recursively()
+        );
+    }
+
+    #[test]
+    fn get_datasets_test() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+
+        let expected_result: BTreeSet<String> = vec![
+            "tank/gentoo/os".to_string(),
+            "tank/lol".to_string(),
+            "tank/home".to_string(),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(expected_result, get_datasets(&snapshots));
+    }
+
+    #[test]
+    fn get_mass_deletion_should_check_limits() {
+        let get_config = |max_delete_percentage, max_delete_count| {
+            Config::new(
+                &utility::FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    max_delete_percentage,
+                    max_delete_count,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(get_mass_deletion(100, 100, &get_config(None, None)).is_none());
+
+        let config = get_config(Some(50.0), None);
+        assert!(get_mass_deletion(50, 100, &config).is_none());
+        assert_eq!(
+            Some(
+                "This would delete 51 of the pool's 100 snapshots (51.00%), which is more than 50%."
+                    .to_string()
+            ),
+            get_mass_deletion(51, 100, &config)
+        );
+
+        let config = get_config(None, Some(10));
+        assert!(get_mass_deletion(10, 100, &config).is_none());
+        assert_eq!(
+            Some("This would delete 11 snapshots, which is more than 10.".to_string()),
+            get_mass_deletion(11, 100, &config)
+        );
+        assert!(get_mass_deletion(0, 0, &config).is_none());
+    }
+
+    #[test]
+    fn calculate_percentage_test() {
+        let numerator: u32 = 42;
+        let denominator: u32 = 20000000;
+        let expected: f32 = 0.00021000001;
+        assert_eq!(expected, calculate_percentage(numerator, denominator));
+    }
+
+    #[test]
+    fn destroy_snapshots_test() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+
+        let mut expected_results: Vec<&Snapshot> = snapshots.iter().collect();
+        let mut results = destroy_snapshots(
+            &utility::FakeCommunicator::new(true),
+            &snapshots,
+            None,
+            &DestroyConfig::new(
+                100,
+                DEFAULT_ARG_MAX,
+                DEFAULT_JOBS,
+                DeletionOrder::Oldest,
+                None,
+                None,
+            ),
+        );
+
+        expected_results.sort();
+        results.sort();
+        assert_eq!(expected_results, results);
+    }
+
+    #[test]
+    fn destroy_snapshots_should_clean_datasets_in_parallel() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+        ];
+
+        let mut results = destroy_snapshots(
+            &communicator,
+            &snapshots,
+            None,
+            &DestroyConfig::new(2, DEFAULT_ARG_MAX, 3, DeletionOrder::Oldest, None, None),
+        );
+
+        let mut expected_results: Vec<&Snapshot> = snapshots.iter().collect();
+        expected_results.sort();
+        results.sort();
+        assert_eq!(expected_results, results);
+
+        let mut destroyed_snapshots = communicator.destroyed_snapshots();
+        destroyed_snapshots.sort();
+        assert_eq!(
+            vec![
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+                "tank/home@2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT",
+                "tank/home@2020-09-05-1320-00-CHECKPOINT",
+                "tank/lol@2020-05-01-1100-00-CHECKPOINT",
+            ],
+            destroyed_snapshots
+        );
+    }
+
+    #[test]
+    fn destroy_snapshots_should_follow_deletion_order() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/b", "2020-09-05-1330-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/b", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/a", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/a", "2020-09-05-1320-00", "CHECKPOINT"),
+        ];
+        let get_destroyed_snapshots = |deletion_order| {
+            let communicator = utility::FakeCommunicator::new(true);
+            let config =
+                DestroyConfig::new(1, DEFAULT_ARG_MAX, DEFAULT_JOBS, deletion_order, None, None);
+            let deleted_snapshots = destroy_snapshots(&communicator, &snapshots, None, &config);
+            let deleted_names: Vec<String> =
+                deleted_snapshots.iter().map(|s| s.to_string()).collect();
+            assert_eq!(communicator.destroyed_snapshots(), deleted_names);
+            deleted_names
+        };
+
+        assert_eq!(
+            vec![
+                "tank/b@2020-09-05-1300-00-CHECKPOINT",
+                "tank/a@2020-09-05-1310-00-CHECKPOINT",
+                "tank/a@2020-09-05-1320-00-CHECKPOINT",
+                "tank/b@2020-09-05-1330-00-CHECKPOINT",
+            ],
+            get_destroyed_snapshots(DeletionOrder::Oldest)
+        );
+        assert_eq!(
+            vec![
+                "tank/a@2020-09-05-1310-00-CHECKPOINT",
+                "tank/a@2020-09-05-1320-00-CHECKPOINT",
+                "tank/b@2020-09-05-1300-00-CHECKPOINT",
+                "tank/b@2020-09-05-1330-00-CHECKPOINT",
+            ],
+            get_destroyed_snapshots(DeletionOrder::Dataset)
+        );
+    }
+
+    #[test]
+    fn destroy_snapshots_should_batch_each_dataset_by_default() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = Config::new(
+            &communicator,
+            Options {
+                pool: "tank".to_string(),
+                ..Default::default()
+            },
+        );
+        let mut snapshots = Vec::new();
+        for hour in 10..20 {
+            for dataset in &["tank", "tank/a", "tank/b"] {
+                let time = format!("2020-09-05-{}00-00", hour);
+                snapshots.push(utility::create_snapshot(dataset, &time, "HOURLY"));
+            }
+        }
+
+        destroy_snapshots(&communicator, &snapshots, None, &config.destroy_config());
+
+        assert_eq!(3, communicator.destroyed_snapshots().len());
+    }
+
+    #[test]
+    fn get_ordered_batches_should_delete_oldest_first_across_batches() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/a", "2020-09-05-1000-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/a", "2020-09-05-1030-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/a", "2020-09-05-1040-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/b", "2020-09-05-1010-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/b", "2020-09-05-1020-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/b", "2020-09-05-1050-00", "CHECKPOINT"),
+        ];
+        let get_batch_names = |deletion_order| {
+            let config =
+                DestroyConfig::new(2, DEFAULT_ARG_MAX, DEFAULT_JOBS, deletion_order, None, None);
+            get_ordered_batches(&snapshots, &config)
+                .iter()
+                .map(|(_, batch)| build_list_to_delete(batch, None))
+                .collect::<Vec<String>>()
+        };
+
+        assert_eq!(
+            vec![
+                "tank/a@2020-09-05-1000-00-CHECKPOINT",
+                "tank/b@2020-09-05-1010-00-CHECKPOINT,2020-09-05-1020-00-CHECKPOINT",
+                "tank/a@2020-09-05-1030-00-CHECKPOINT,2020-09-05-1040-00-CHECKPOINT",
+                "tank/b@2020-09-05-1050-00-CHECKPOINT",
+            ],
+            get_batch_names(DeletionOrder::Oldest)
+        );
+        assert_eq!(
+            vec![
+                "tank/a@2020-09-05-1000-00-CHECKPOINT,2020-09-05-1030-00-CHECKPOINT",
+                "tank/a@2020-09-05-1040-00-CHECKPOINT",
+                "tank/b@2020-09-05-1010-00-CHECKPOINT,2020-09-05-1020-00-CHECKPOINT",
+                "tank/b@2020-09-05-1050-00-CHECKPOINT",
+            ],
+            get_batch_names(DeletionOrder::Dataset)
+        );
+    }
+
+    #[test]
+    fn destroy_snapshots_should_stop_at_max_deletions() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1330-00", "CHECKPOINT"),
+        ];
+        let config = DestroyConfig::new(
+            2,
+            DEFAULT_ARG_MAX,
+            DEFAULT_JOBS,
+            DeletionOrder::Oldest,
+            None,
+            Some(3),
+        );
+
+        let results = destroy_snapshots(&communicator, &snapshots, None, &config);
+
+        assert_eq!(vec![&snapshots[0], &snapshots[1], &snapshots[2]], results);
+        assert_eq!(
+            vec![
+                "tank/home@2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT",
+                "tank/home@2020-09-05-1320-00-CHECKPOINT",
+            ],
+            communicator.destroyed_snapshots()
+        );
+    }
+
+    #[test]
+    fn destroy_snapshots_should_stop_at_max_runtime() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/lol", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+        let max_runtime = Some(std::time::Duration::from_secs(0));
+
+        for jobs in &[1, 2] {
+            let config = DestroyConfig::new(
+                1,
+                DEFAULT_ARG_MAX,
+                *jobs,
+                DeletionOrder::Oldest,
+                max_runtime,
+                None,
+            );
+            let results = destroy_snapshots(&communicator, &snapshots, None, &config);
+            assert!(results.is_empty());
+        }
+        assert!(communicator.destroyed_snapshots().is_empty());
+    }
+
+    #[test]
+    fn destroy_snapshots_should_share_max_deletions_between_jobs() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshots = vec![
+            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
+            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+        let config =
+            DestroyConfig::new(1, DEFAULT_ARG_MAX, 3, DeletionOrder::Oldest, None, Some(2));
+
+        let results = destroy_snapshots(&communicator, &snapshots, None, &config);
+
+        assert_eq!(2, results.len());
+        assert_eq!(2, communicator.destroyed_snapshots().len());
+    }
+
+    #[test]
+    fn parse_runtime_test() {
+        assert_eq!(
+            Some(std::time::Duration::from_secs(90)),
+            parse_runtime("90s")
+        );
+        assert_eq!(
+            Some(std::time::Duration::from_secs(1800)),
+            parse_runtime("30m")
+        );
+        assert_eq!(
+            Some(std::time::Duration::from_secs(7200)),
+            parse_runtime("2H")
+        );
+        assert_eq!(None, parse_runtime("30d"));
+        assert_eq!(None, parse_runtime("m"));
+        assert_eq!(None, parse_runtime(""));
+    }
+
+    #[test]
+    fn get_batches_should_respect_iteration_amount() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+        ];
+
+        let batches = get_batches(&snapshots, 2, DEFAULT_ARG_MAX);
+
+        assert_eq!(
+            vec![(
+                "tank/home".to_string(),
+                vec![vec![&snapshots[0], &snapshots[1]], vec![&snapshots[2]]]
+            )],
+            batches
+        );
+    }
+
+    #[test]
+    fn get_batches_should_respect_max_argument_length() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+        ];
+        // tank/home@2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT
+        let length = build_list_to_delete(&[&snapshots[0], &snapshots[1]], None).len();
+
+        let batches = get_batches(&snapshots, 100, length);
+        assert_eq!(
+            vec![(
+                "tank/home".to_string(),
+                vec![vec![&snapshots[0], &snapshots[1]], vec![&snapshots[2]]]
+            )],
+            batches
+        );
+
+        let batches = get_batches(&snapshots, 100, length - 1);
+        assert_eq!(
+            vec![(
+                "tank/home".to_string(),
+                vec![
+                    vec![&snapshots[0]],
+                    vec![&snapshots[1]],
+                    vec![&snapshots[2]]
+                ]
+            )],
+            batches
+        );
+
+        let batches = get_batches(&snapshots, 100, 1);
+        assert_eq!(3, batches[0].1.len());
+    }
+
+    #[test]
+    fn get_default_max_argument_length_test() {
+        let communicator = utility::FakeCommunicator::new(true);
+        if cfg!(target_os = "linux") {
+            assert_eq!(
+                LINUX_MAX_ARGUMENT_LENGTH,
+                get_default_max_argument_length(&communicator)
+            );
+        } else {
+            assert_eq!(1_048_576, get_default_max_argument_length(&communicator));
+        }
+    }
+
+    #[test]
+    fn build_script_test() {
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1320-00", "CHECKPOINT"),
+        ];
+
+        let script = build_script(
+            "tank",
+            &snapshots,
+            None,
+            &DestroyConfig::new(
+                2,
+                DEFAULT_ARG_MAX,
+                DEFAULT_JOBS,
+                DeletionOrder::Oldest,
+                None,
+                None,
+            ),
+            utility::get_fake_now(),
+        );
+
+        let expected_script = format!(
+            "#!/bin/sh\n\
+            # Generated by Honeydew {} at 2020-08-23-1023-17\n\
+            # Pool: tank\n\
+            # Amount of Snapshots to Remove: 3\n\
+            set -e\n\
+            \n\
+            # Cleaning snapshots for tank/home\n\
+            zfs list -H -o name -t snapshot 'tank/home@2020-09-05-1300-00-CHECKPOINT' 'tank/home@2020-09-05-1310-00-CHECKPOINT' > /dev/null\n\
+            zfs destroy 'tank/home@2020-09-05-1300-00-CHECKPOINT,2020-09-05-1310-00-CHECKPOINT'\n\
+            zfs list -H -o name -t snapshot 'tank/home@2020-09-05-1320-00-CHECKPOINT' > /dev/null\n\
+            zfs destroy 'tank/home@2020-09-05-1320-00-CHECKPOINT'\n",
+            APP_VERSION
+        );
+        assert_eq!(expected_script, script);
+    }
+
+    #[test]
+    fn quote_shell_test() {
+        assert_eq!("'tank@manual'", quote_shell("tank@manual"));
+        assert_eq!("'tank@it'\\''s'", quote_shell("tank@it's"));
+    }
+
+    #[test]
+    fn emit_script_should_write_script() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = Config::new(
+            &communicator,
+            Options {
+                pool: "tank".to_string(),
+                emit_script: "out.sh".to_string(),
+                ..Default::default()
+            },
+        );
+        let snapshots = vec![utility::create_snapshot(
+            "tank/home",
+            "2020-09-05-1300-00",
+            "CHECKPOINT",
+        )];
+
+        emit_script(&communicator, &config, &snapshots, utility::get_fake_now()).unwrap();
+
+        assert!(communicator
+            .written_script()
+            .ends_with("zfs destroy 'tank/home@2020-09-05-1300-00-CHECKPOINT'\n"));
+    }
+
+    #[test]
+    fn build_channel_program_test() {
+        let snapshots = [
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+
+        let program = build_channel_program(&[&snapshots[0], &snapshots[1]]);
+
+        assert!(program.starts_with(&format!(
+            "-- Generated by Honeydew {}\n\
+            snapshots = {{\n    \
+            \"tank/home@2020-09-05-1300-00-CHECKPOINT\",\n    \
+            \"tank@2020-09-05-1310-00-CHECKPOINT\",\n\
+            }}\n",
+            APP_VERSION
+        )));
+        assert!(program.contains("zfs.check.destroy(snapshot)"));
+        assert!(program.ends_with("return #snapshots\n"));
+    }
+
+    #[test]
+    fn quote_lua_test() {
+        assert_eq!("\"tank@manual\"", quote_lua("tank@manual"));
+        assert_eq!("\"tank@\\\"a\\\\b\"", quote_lua("tank@\"a\\b"));
+    }
+
+    #[test]
+    fn destroy_with_channel_program_should_run_one_program() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = Config::new(
+            &communicator,
+            Options {
+                pool: "tank".to_string(),
+                backend: Backend::ChannelProgram,
+                ..Default::default()
+            },
+        );
+        let snapshots = vec![
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+            utility::create_snapshot("tank", "2020-09-05-1310-00", "CHECKPOINT"),
+        ];
+
+        let deleted_snapshots =
+            destroy_with_channel_program(&communicator, &config, &snapshots).unwrap();
+
+        // The datasets are cleaned in order of their names.
+        assert_eq!(2, deleted_snapshots.len());
+        assert_eq!(
+            vec![build_channel_program(&[&snapshots[1], &snapshots[0]])],
+            communicator.channel_programs()
+        );
+    }
+
+    #[test]
+    fn destroy_with_channel_program_should_stop_at_max_deletions() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = Config::new(
+            &communicator,
+            Options {
+                pool: "tank".to_string(),
+                backend: Backend::ChannelProgram,
+                max_deletions: Some(1),
+                deletion_order: DeletionOrder::Oldest,
+                ..Default::default()
+            },
+        );
+        let snapshots = vec![
+            utility::create_snapshot("tank", "2020-09-05-1310-00", "CHECKPOINT"),
+            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
+        ];
+
+        let deleted_snapshots =
+            destroy_with_channel_program(&communicator, &config, &snapshots).unwrap();
+
+        assert_eq!(vec![&snapshots[1]], deleted_snapshots);
+        assert_eq!(
+            vec![build_channel_program(&[&snapshots[1]])],
+            communicator.channel_programs()
         );
-        deleted_snapshots.append(queued_snapshots);
-    };
+    }
 
-    // Snapshots deleted per round need to be all in the same dataset
-    // since it will be batched to ZFS for optimization.
-    for dataset in get_datasets(&snapshots) {
-        println!("Cleaning snapshots for {} ...\n", dataset);
-        let snapshots_for_dataset: Vec<&Snapshot> = snapshots
-            .iter()
-            .filter(|snapshot| snapshot.dataset() == &dataset)
-            .collect();
+    #[test]
+    fn get_snapshot_names_test() {
+        let datasets = vec!["tank".to_string(), "boot/os".to_string()];
+        let time = Local.ymd(2020, 8, 23).and_hms(10, 23, 17);
+        let expected_names = vec![
+            "tank@2020-08-23-1023-17-ANIMALS",
+            "boot/os@2020-08-23-1023-17-ANIMALS",
+        ];
+        assert_eq!(
+            expected_names,
+            get_snapshot_names(&datasets, "ANIMALS", time)
+        );
+    }
 
-        // Load up all the snapshots up to the iteration amount. If the
-        // iteration amount is greater than the total amount of snapshots
-        // that are listed for this dataset, then they will all end up being
-        // cleaned when we empty the chamber, since the % code below will
-        // never fire. This is by design.
-        for snapshot in snapshots_for_dataset.iter() {
-            queued_snapshots.push(snapshot);
-            if queued_snapshots.len() as u32 % iteration_amount == 0 {
-                cleaner(
-                    &mut total_processed,
-                    &mut queued_snapshots,
-                    communicator,
-                    snapshot_count,
-                    &mut deleted_snapshots,
-                );
-            }
-        }
+    #[test]
+    fn take_snapshots_should_create_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = SnapshotConfig::new(vec!["tank".to_string()], "ANIMALS", true);
+        let time = Local.ymd(2020, 8, 23).and_hms(10, 23, 17);
 
-        // Empty the chamber ;..;
-        if queued_snapshots.len() != 0 {
-            cleaner(
-                &mut total_processed,
-                &mut queued_snapshots,
-                communicator,
-                snapshot_count,
-                &mut deleted_snapshots,
-            );
-        }
+        take_snapshots(&communicator, &config, time).unwrap();
 
-        println!("");
+        assert_eq!(
+            vec!["tank@2020-08-23-1023-17-ANIMALS"],
+            communicator.created_snapshots()
+        );
+        assert!(communicator.created_recursively());
     }
 
-    if queued_snapshots.len() != 0 {
-        // We should never get here if the program is behaving correctly.
-        // All the snapshots should be completely deleted by this point.
-        println!("These were the remaining snapshots:");
-        println!("----------------");
-        for snapshot in &queued_snapshots {
-            println!("{}", snapshot);
+    #[test]
+    fn rotate_should_not_clean_if_snapshots_fail() {
+        pub struct FakeCommunicator;
+        impl Communicator for FakeCommunicator {
+            fn create_snapshots(&self, _snapshots: &[String], _recursive: bool) -> SystemResult {
+                Err(enums::SystemError::CreateSnapshots(
+                    "out of space".to_string(),
+                ))
+            }
         }
-        panic!(
-            "There are still {} snapshots in the queue! Please file a bug report!\n",
-            queued_snapshots.len()
-        )
+
+        // Listing or destroying snapshots would panic on this communicator.
+        let snapshot_config = SnapshotConfig::new(vec!["tank".to_string()], "ANIMALS", false);
+        let result = rotate(
+            &FakeCommunicator,
+            &snapshot_config,
+            &utility::get_fake_config("tank", "2099-01-01-0000-00", "ANIMALS"),
+            Local::now(),
+        );
+        assert!(result.is_err());
     }
-    deleted_snapshots
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use testing::utility;
+    #[test]
+    fn rotate_should_not_take_snapshots_in_a_dry_run() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let snapshot_config = SnapshotConfig::new(vec!["tank".to_string()], "ANIMALS", false);
+        let config = Config::new(
+            &communicator,
+            Options {
+                pool: "tank".to_string(),
+                label: "ANIMALS".to_string(),
+                dry_run: true,
+                ..Default::default()
+            },
+        );
+
+        rotate(&communicator, &snapshot_config, &config, Local::now()).unwrap();
+
+        assert!(communicator.created_snapshots().is_empty());
+    }
 
     #[test]
-    fn get_stale_snapshots_test() {
-        let snapshots = vec![
-            utility::create_snapshot("tank/tmp", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/poudriere", "2020-08-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-09-13-2354-09", "CHECKPOINT"),
-        ];
+    fn pin_should_append_to_exclude_file() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT",
+            "exclude.txt",
+            Pin::new("jon", "Before upgrading", ""),
+        );
 
-        let cutoff_date = Local.ymd(2020, 09, 10).and_hms(0, 0, 0);
+        pin(&communicator, &config).unwrap();
 
-        let expected_snapshots = vec![
-            utility::create_snapshot("tank/tmp", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/poudriere", "2020-08-13-2354-09", "CHECKPOINT"),
-        ];
+        assert_eq!(
+            "boot@2020-08-12-1237-49-CHECKPOINT\n\
+            tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n\
+            tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT owner=jon # Before upgrading\n",
+            communicator.written_exclude_file()
+        );
+    }
 
-        let stale_snapshots = get_stale_snapshots(snapshots, &cutoff_date);
+    #[test]
+    fn pin_should_create_missing_exclude_file() {
+        let communicator = utility::FakeCommunicator::new(false);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT",
+            "exclude.txt",
+            Pin::default(),
+        );
 
-        assert_eq!(expected_snapshots, stale_snapshots);
+        pin(&communicator, &config).unwrap();
+
+        assert_eq!(
+            "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT\n",
+            communicator.written_exclude_file()
+        );
     }
 
     #[test]
-    fn parse_snapshot_should_return_none() {
-        let snapshot = "boot@lol";
+    fn pin_should_not_duplicate_entries() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+            "exclude.txt",
+            Pin::default(),
+        );
 
-        let result = parse_snapshot(&snapshot);
+        pin(&communicator, &config).unwrap();
 
-        assert_eq!(None, result);
+        assert!(communicator.written_exclude_file().is_empty());
     }
 
     #[test]
-    fn get_parsed_snapshots_should_return_snapshots() {
-        let snapshots: Vec<String> = [
-            "elephants/in/space@2022-12-31-0000-00-CHECKPOINT",
-            "boot@2020-08-12-1237-49-CHECKPOINT",
-            "boot@2020-08-12-1237-49-CHECKPOINT",
-            "tank/tmp@2020-08-12-1237-49-CHECKPOINT",
-            "tank/poudriere@2020-08-12-1237-49-CHECKPOINT",
-            "tank/os/main@2020-08-12-1237-49-CHECKPOINT",
-            "tank/os/main@2022-10-09-1234-60-CHECKPOINT",
-        ]
-        .iter()
-        .map(|&name| String::from(name))
-        .collect();
+    fn pin_should_set_protect_property() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT",
+            "",
+            Pin::new("jon", "Before upgrading", ""),
+        );
 
-        let expected_snapshots: Vec<Snapshot> = snapshots
-            .iter()
-            .map(|snapshot| utility::create_snapshot_from_string(&snapshot))
-            .collect();
+        pin(&communicator, &config).unwrap();
+
+        assert_eq!(
+            vec!["set honeydew:protect=on owner=jon # Before upgrading tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT"],
+            communicator.changed_properties()
+        );
+    }
 
-        let result_snapshots = get_parsed_snapshots(snapshots.clone());
+    #[test]
+    #[should_panic(expected = "Snapshot doesn't exist")]
+    fn pin_should_panic_for_missing_snapshot() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-09-13-2354-09-CHECKPOINT",
+            "",
+            Pin::default(),
+        );
 
-        assert_eq!(result_snapshots.len(), 7);
-        assert_eq!(result_snapshots, expected_snapshots);
+        pin(&communicator, &config).unwrap();
     }
 
     #[test]
-    fn get_snapshots_for_should_filter_correctly() {
-        let initial_snapshots = vec![
-            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
-            utility::create_snapshot(
-                "backup/tank/gentoo/home",
-                "2020-07-13-2354-09",
-                "CHECKPOINT",
-            ),
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "LOL"),
-        ];
+    fn unpin_should_remove_from_exclude_file() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
+            "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+            "exclude.txt",
+            Pin::default(),
+        );
 
-        let expected_snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-08-13-2354-09", "CHECKPOINT"),
-        ];
+        unpin(&communicator, &config).unwrap();
 
         assert_eq!(
-            expected_snapshots,
-            get_snapshots_for("tank", "CHECKPOINT", initial_snapshots)
+            "boot@2020-08-12-1237-49-CHECKPOINT\n",
+            communicator.written_exclude_file()
         );
     }
 
     #[test]
-    fn all_snapshots_should_be_retrieved() {
-        let expected_snapshots = vec![
-            "boot@2020-08-12-1237-49-CHECKPOINT",
-            "backup/tank/gentoo/home@2020-07-13-2354-09-CHECKPOINT",
+    #[should_panic(expected = "File doesn't exist")]
+    fn unpin_should_panic_for_missing_exclude_file() {
+        let communicator = utility::FakeCommunicator::new(false);
+        let config = utility::get_fake_pin_config(
             "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+            "exclude.txt",
+            Pin::default(),
+        );
+
+        unpin(&communicator, &config).unwrap();
+    }
+
+    #[test]
+    fn unpin_should_inherit_protect_property() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_pin_config(
             "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT",
-        ];
+            "",
+            Pin::default(),
+        );
+
+        unpin(&communicator, &config).unwrap();
 
         assert_eq!(
-            expected_snapshots,
-            get_snapshots(&utility::FakeCommunicator::new(true))
+            vec!["inherit honeydew:protect tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT"],
+            communicator.changed_properties()
         );
     }
 
     #[test]
-    fn get_excluded_snapshots_test() {
-        let expected_snapshots = vec![utility::create_snapshot(
-            "boot",
-            "2020-08-12-1237-49",
-            "CHECKPOINT",
-        )];
+    fn get_file_pins_should_filter_by_pool() {
+        let exclude_lines: Vec<String> = [
+            "tank@2020-07-13-2354-09-CHECKPOINT owner=jon # Keep",
+            "boot@2020-08-12-1237-49-CHECKPOINT",
+            "*@*-RELEASE",
+            "# tank@2020-01-01-2354-09-CHECKPOINT",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+
+        let pins = get_file_pins(&exclude_lines, "tank");
+
+        assert_eq!(2, pins.len());
+        assert_eq!(&Pin::new("jon", "Keep", ""), pins[0].pin());
+        assert_eq!("*@*-RELEASE", pins[1].pattern().to_string());
+    }
+
+    #[test]
+    fn get_property_pins_should_include_undated_names() {
+        let lines: Vec<String> = [
+            "tank@2020-07-13-2354-09-CHECKPOINT\t1594698849\ton owner=jon",
+            "tank/home@before-upgrade\t1594698849\ton",
+            "tank/home@2020-07-14-2354-09-CHECKPOINT\t1594785249\t-",
+            "boot@before-upgrade\t1594698849\ton",
+        ]
+        .iter()
+        .map(|line| line.to_string())
+        .collect();
+
+        let names: Vec<String> = get_property_pins(&lines, "tank")
+            .into_iter()
+            .map(|(name, _)| name)
+            .collect();
 
         assert_eq!(
-            expected_snapshots,
-            get_excluded_snapshots(
-                &utility::FakeCommunicator::new(true),
-                &utility::get_fake_config("boot", "2020-05-01-1200-00", "")
-            )
+            vec![
+                "tank@2020-07-13-2354-09-CHECKPOINT",
+                "tank/home@before-upgrade"
+            ],
+            names
         );
     }
 
     #[test]
-    fn get_relevant_snapshots_test() {
-        pub struct FakeCommunicator;
-        impl Communicator for FakeCommunicator {
-            fn get_snapshots(&self) -> SystemResult {
-                Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
-                    tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n\
-                    tank/gentoo/os@2020-05-01-1100-00-CHECKPOINT\n\
-                    tank/gentoo/home@2020-04-25-1300-15-CHECKPOINT\n\
-                    tank@2020-01-01-2354-09-CHECKPOINT\n"
-                    .to_string())
-            }
-            fn get_excluded_snapshots(&self, _exclude_file: &str) -> SystemResult {
-                Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
-                tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n"
-                    .to_string())
-            }
-        }
-
-        let excluded_snapshots = vec![
-            utility::create_snapshot("tank/gentoo/home", "2020-04-25-1300-15", "CHECKPOINT"), // older but excluded
-        ];
-        let expected_snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
-            utility::create_snapshot("tank", "2020-01-01-2354-09", "CHECKPOINT"),
-        ];
-        let relevant_snapshots = get_relevant_snapshots(
-            &FakeCommunicator,
-            &utility::get_fake_config("tank", "2020-05-01-1200-00", ""),
-            &excluded_snapshots,
+    fn parse_protection_test() {
+        assert!(parse_protection("-").is_none());
+        assert!(parse_protection("off").is_none());
+        assert_eq!(Some(Pin::default()), parse_protection("on"));
+        assert_eq!(Some(Pin::default()), parse_protection("YES"));
+        assert_eq!(
+            Some(Pin::new("jon", "Before upgrading", "")),
+            parse_protection("on owner=jon # Before upgrading")
         );
-        assert_eq!(expected_snapshots, relevant_snapshots);
+        assert_eq!(Some(Pin::default()), parse_protection("on colour=blue"));
     }
 
     #[test]
-    fn remove_excluded_snapshots_test() {
-        let snapshots = vec![
-            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/home", "2020-04-25-1300-15", "CHECKPOINT"),
-            utility::create_snapshot("tank", "2020-01-01-2354-09", "CHECKPOINT"),
-        ];
+    fn parse_exclusion_should_read_pin_details() {
+        let exclusion = parse_exclusion("tank@2020-07-13-2354-09-CHECKPOINT owner=jon # Keep it")
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            "tank@2020-07-13-2354-09-CHECKPOINT",
+            exclusion.pattern().to_string()
+        );
+        assert_eq!(&Pin::new("jon", "Keep it", ""), exclusion.pin());
+        assert!(parse_exclusion("tank@2020-07-13-2354-09-CHECKPOINT colour=blue").is_err());
+    }
 
-        let excluded_snapshots = vec![
-            utility::create_snapshot("boot", "2020-08-12-1237-49", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/home", "2020-04-25-1300-15", "CHECKPOINT"),
-            utility::create_snapshot("tank", "2020-01-01-2354-09", "CHECKPOINT"),
-        ];
+    #[test]
+    fn plan_should_write_queued_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let config = utility::get_fake_config("tank", "2099-01-01-0000-00", "");
+        let time = utility::get_fake_now();
 
-        let expected_snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
-        ];
+        assert!(plan(&communicator, &config, "plan.json", time).unwrap());
 
+        let written_plan = Plan::from_json(&communicator.written_plan()).unwrap();
         assert_eq!(
-            expected_snapshots,
-            remove_excluded_snapshots(snapshots, &excluded_snapshots)
-        )
+            &Plan::new(
+                "tank",
+                time,
+                "",
+                DEFAULT_PROTECT_PROPERTY,
+                vec!["tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT".to_string()]
+            ),
+            &written_plan
+        );
     }
 
     #[test]
-    fn build_list_to_delete_test() {
-        let snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-05-01-1100-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/gentoo/os", "2020-09-05-1300-00", "CHECKPOINT"),
-        ];
-        let references = snapshots.iter().collect();
-        let expected_result = "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT,2020-05-01-1100-00-CHECKPOINT,2020-09-05-1300-00-CHECKPOINT";
-        assert_eq!(expected_result, build_list_to_delete(&references));
+    fn plan_should_refuse_mass_deletions() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let time = utility::get_fake_now();
+        let get_config = |allow_mass_deletion| {
+            Config::new(
+                &communicator,
+                Options {
+                    pool: "tank".to_string(),
+                    date: "2099-01-01-0000-00".to_string(),
+                    no_confirm: true,
+                    max_delete_count: Some(0),
+                    allow_mass_deletion,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(!plan(&communicator, &get_config(false), "plan.json", time).unwrap());
+        assert!(communicator.written_plan().is_empty());
+
+        assert!(plan(&communicator, &get_config(true), "plan.json", time).unwrap());
+        assert!(!communicator.written_plan().is_empty());
     }
 
     #[test]
-    fn get_datasets_test() {
-        let snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
-        ];
-
-        let expected_result: HashSet<String> = vec![
-            "tank/gentoo/os".to_string(),
-            "tank/lol".to_string(),
-            "tank/home".to_string(),
-        ]
-        .into_iter()
-        .collect();
+    fn get_planned_snapshots_should_return_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let time = utility::get_fake_now();
+        let plan = Plan::new(
+            "tank",
+            time - Duration::hours(23),
+            "",
+            DEFAULT_PROTECT_PROPERTY,
+            vec![
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT".to_string(),
+                "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT".to_string(),
+            ],
+        );
 
-        assert_eq!(expected_result, get_datasets(&snapshots));
+        let planned_snapshots = get_planned_snapshots(
+            &communicator,
+            &plan,
+            &utility::get_fake_apply_config("24h"),
+            time,
+        )
+        .unwrap();
+        let names: Vec<String> = planned_snapshots.iter().map(|s| s.to_string()).collect();
+        assert_eq!(plan.snapshots(), &names);
     }
 
     #[test]
-    fn calculate_percentage_test() {
-        let numerator: u32 = 42;
-        let denominator: u32 = 20000000;
-        let expected: f32 = 0.00021000001;
-        assert_eq!(expected, calculate_percentage(numerator, denominator));
+    fn get_planned_snapshots_should_refuse_stale_plans() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let time = utility::get_fake_now();
+        let plan = Plan::new(
+            "tank",
+            time - Duration::hours(25),
+            "",
+            DEFAULT_PROTECT_PROPERTY,
+            vec!["tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT".to_string()],
+        );
+
+        assert!(get_planned_snapshots(
+            &communicator,
+            &plan,
+            &utility::get_fake_apply_config("24h"),
+            time
+        )
+        .is_err());
     }
 
     #[test]
-    fn destroy_snapshots_test() {
-        let snapshots = vec![
-            utility::create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT"),
-            utility::create_snapshot("tank/lol", "2020-05-01-1100-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/home", "2020-09-05-1300-00", "CHECKPOINT"),
-            utility::create_snapshot("tank/home", "2020-09-05-1310-00", "CHECKPOINT"),
-        ];
+    fn get_planned_snapshots_should_refuse_missing_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let time = utility::get_fake_now();
+        let plan = Plan::new(
+            "tank",
+            time,
+            "",
+            DEFAULT_PROTECT_PROPERTY,
+            vec![
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT".to_string(),
+                "tank/gentoo/os@2020-06-13-2354-09-CHECKPOINT".to_string(),
+            ],
+        );
 
-        let mut expected_results: Vec<&Snapshot> = snapshots.iter().collect();
-        let mut results = destroy_snapshots(&utility::FakeCommunicator::new(true), &snapshots, 100);
+        assert_eq!(
+            Err("The plan refers to 1 snapshots that no longer exist.".to_string()),
+            get_planned_snapshots(
+                &communicator,
+                &plan,
+                &utility::get_fake_apply_config("24h"),
+                time
+            )
+        );
+    }
 
-        expected_results.sort();
-        results.sort();
-        assert_eq!(expected_results, results);
+    #[test]
+    fn get_planned_snapshots_should_refuse_pinned_snapshots() {
+        let communicator = utility::FakeCommunicator::new(true);
+        let time = utility::get_fake_now();
+        // The exclude file pins tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT.
+        let plan = Plan::new(
+            "tank",
+            time,
+            "exclude.txt",
+            DEFAULT_PROTECT_PROPERTY,
+            vec![
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT".to_string(),
+                "tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT".to_string(),
+            ],
+        );
+
+        assert_eq!(
+            Err("The plan refers to 1 snapshots that are now protected.".to_string()),
+            get_planned_snapshots(
+                &communicator,
+                &plan,
+                &utility::get_fake_apply_config("24h"),
+                time
+            )
+        );
     }
 
     #[test]
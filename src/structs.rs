@@ -23,69 +23,315 @@
 // OUT OF THE USE OF THIS SOFTWARE, EVEN IF ADVISED OF THE POSSIBILITY OF
 // SUCH DAMAGE.
 
-use super::enums::{SystemError, SystemResult};
-use super::get_cutoff_date;
+use super::enums::{
+    Backend, CutoffAnchor, DeletionOrder, Pattern, SystemError, SystemResult, TimestampSource,
+};
 use super::traits::Communicator;
-use super::SNAPSHOT_FORMAT;
+use super::{
+    get_config_entries, get_cutoff_date, get_default_max_argument_length, label_matches_any,
+    parse_lifetime, parse_runtime, parse_snapshot, split_labels,
+};
+use super::{
+    DEFAULT_CREATION_TOLERANCE, DEFAULT_EXPIRES_PROPERTY, DEFAULT_ITERATIONS, DEFAULT_JOBS,
+    DEFAULT_KEEP_NEWEST, DEFAULT_PROTECT_PROPERTY, PIN_DATE_FORMAT, SNAPSHOT_FORMAT, TRASH_MARKER,
+};
 use chrono::prelude::*;
+use chrono::Duration;
+use regex::Regex;
 use std::fmt;
 use std::fs::File;
 use std::io::prelude::*;
+use std::os::unix::fs::PermissionsExt;
 use std::path::Path;
 use std::process::{Command, Stdio};
+/// The options that a configuration is built from.
+#[derive(Debug)]
+pub struct Options {
+    pub pool: String,
+    pub date: String,
+    pub exclude_file: String,
+    pub config_file: String,
+    pub emit_script: String,
+    pub show_queued: bool,
+    pub show_excluded: bool,
+    pub dry_run: bool,
+    pub iteration_count: u32,
+    pub max_argument_length: Option<usize>,
+    pub jobs: usize,
+    pub deletion_order: DeletionOrder,
+    pub max_runtime: String,
+    pub max_deletions: Option<usize>,
+    pub no_confirm: bool,
+    pub label: String,
+    pub exclude_label: String,
+    pub ignore_case: bool,
+    pub max_age: String,
+    pub include_datasets: Vec<String>,
+    pub exclude_datasets: Vec<String>,
+    pub keep_newest: usize,
+    pub max_delete_percentage: Option<f32>,
+    pub max_delete_count: Option<usize>,
+    pub allow_mass_deletion: bool,
+    pub quarantine: bool,
+    pub use_ranges: bool,
+    pub recursive_sets: bool,
+    pub backend: Backend,
+    pub instruction_limit: Option<u64>,
+    pub memory_limit: Option<u64>,
+    pub show_config: bool,
+    pub timestamp_source: TimestampSource,
+    pub cutoff_anchor: CutoffAnchor,
+    pub creation_tolerance: i64,
+    pub strict_excludes: bool,
+    pub label_expiry: bool,
+    pub protect_property: String,
+    pub expires_property: String,
+}
+
+impl Default for Options {
+    fn default() -> Options {
+        Options {
+            pool: String::new(),
+            date: String::new(),
+            exclude_file: String::new(),
+            config_file: String::new(),
+            emit_script: String::new(),
+            show_queued: false,
+            show_excluded: false,
+            dry_run: false,
+            iteration_count: DEFAULT_ITERATIONS,
+            max_argument_length: None,
+            jobs: DEFAULT_JOBS,
+            deletion_order: DeletionOrder::Dataset,
+            max_runtime: String::new(),
+            max_deletions: None,
+            no_confirm: false,
+            label: String::new(),
+            exclude_label: String::new(),
+            ignore_case: false,
+            max_age: String::new(),
+            include_datasets: Vec::new(),
+            exclude_datasets: Vec::new(),
+            keep_newest: DEFAULT_KEEP_NEWEST,
+            max_delete_percentage: None,
+            max_delete_count: None,
+            allow_mass_deletion: false,
+            quarantine: false,
+            use_ranges: false,
+            recursive_sets: false,
+            backend: Backend::Batched,
+            instruction_limit: None,
+            memory_limit: None,
+            show_config: false,
+            timestamp_source: TimestampSource::Name,
+            cutoff_anchor: CutoffAnchor::Now,
+            creation_tolerance: DEFAULT_CREATION_TOLERANCE,
+            strict_excludes: false,
+            label_expiry: false,
+            protect_property: DEFAULT_PROTECT_PROPERTY.to_string(),
+            expires_property: DEFAULT_EXPIRES_PROPERTY.to_string(),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Config {
     pool: String,
     date: DateTime<Local>,
+    fixed_date: bool,
     exclude_file: String,
+    config_file: String,
+    emit_script: String,
     show_queued: bool,
     show_excluded: bool,
     dry_run: bool,
     iteration_count: u32,
+    max_argument_length: usize,
+    jobs: usize,
+    deletion_order: DeletionOrder,
+    max_runtime: String,
+    max_deletions: Option<usize>,
     no_confirm: bool,
-    label: String,
+    labels: Vec<String>,
+    excluded_labels: Vec<String>,
+    ignore_case: bool,
+    max_ages: Vec<MaxAge>,
+    include_datasets: Vec<String>,
+    exclude_datasets: Vec<String>,
+    keep_newest: usize,
+    max_delete_percentage: Option<f32>,
+    max_delete_count: Option<usize>,
+    allow_mass_deletion: bool,
+    quarantine: bool,
+    use_ranges: bool,
+    recursive_sets: bool,
+    backend: Backend,
+    instruction_limit: Option<u64>,
+    memory_limit: Option<u64>,
     show_config: bool,
+    timestamp_source: TimestampSource,
+    cutoff_anchor: CutoffAnchor,
+    time: DateTime<Local>,
+    creation_tolerance: i64,
+    strict_excludes: bool,
+    label_expiry: bool,
+    protect_property: String,
+    expires_property: String,
 }
 
 impl Config {
-    pub fn new<T: Communicator>(
-        communicator: &T,
-        pool: &str,
-        date: &str,
-        exclude_file: &str,
-        show_queued: bool,
-        show_excluded: bool,
-        dry_run: bool,
-        iteration_count: u32,
-        no_confirm: bool,
-        label: &str,
-        show_config: bool,
-    ) -> Config {
-        let cutoff_date: DateTime<Local>;
-        if date.is_empty() {
-            cutoff_date = get_cutoff_date(Local::now());
+    pub fn new<T: Communicator>(communicator: &T, options: Options) -> Config {
+        let now = Local::now();
+        let cutoff_date = if options.date.is_empty() {
+            get_cutoff_date(now)
         } else {
-            cutoff_date = match Local.datetime_from_str(&date, SNAPSHOT_FORMAT) {
+            match Local.datetime_from_str(&options.date, SNAPSHOT_FORMAT) {
                 Err(_) => panic!("Error parsing date: Example: 2017-09-26-1111-00"),
                 Ok(v) => v,
-            };
+            }
+        };
+        if !options.exclude_file.is_empty() && !communicator.does_file_exist(&options.exclude_file)
+        {
+            panic!("File doesn't exist: {}", options.exclude_file);
+        }
+        if let Some(percentage) = options.max_delete_percentage {
+            if !(0.0..=100.0).contains(&percentage) {
+                panic!(
+                    "Invalid percentage (It must be between 0 and 100): {}",
+                    percentage
+                );
+            }
+        }
+        if options.jobs == 0 {
+            panic!("Invalid amount of jobs (It must be at least 1): 0");
+        }
+        if !options.emit_script.is_empty() && options.quarantine {
+            panic!(
+                "A script can't quarantine snapshots. Use either --emit-script or --quarantine."
+            );
         }
-        if !exclude_file.is_empty() {
-            if !communicator.does_file_exist(&exclude_file) {
-                panic!("File doesn't exist: {}", exclude_file);
+        if options.backend == Backend::ChannelProgram
+            && (options.quarantine || options.recursive_sets)
+        {
+            panic!(
+                "The channel program backend can't be used with --quarantine or --recursive-sets."
+            );
+        }
+        // Recursive sets and channel programs don't destroy the snapshots in
+        // batches, so the options for the batches would be ignored.
+        let unbatched_mode = if options.recursive_sets {
+            "--recursive-sets"
+        } else if options.backend == Backend::ChannelProgram {
+            "--backend channel-program"
+        } else {
+            ""
+        };
+        if !unbatched_mode.is_empty() {
+            let batch_options = [
+                (options.jobs > 1, "--jobs"),
+                (options.use_ranges, "--ranges"),
+                (
+                    options.iteration_count != DEFAULT_ITERATIONS,
+                    "--per-iteration",
+                ),
+            ];
+            if let Some((_, option)) = batch_options.iter().find(|(used, _)| *used) {
+                panic!(
+                    "{} can't be used with {}, since the snapshots aren't destroyed in batches.",
+                    option, unbatched_mode
+                );
             }
         }
+        if !options.max_runtime.is_empty() && options.backend == Backend::ChannelProgram {
+            panic!("A channel program can't be stopped once it runs. Use --max-deletions to limit it instead of --max-runtime.");
+        }
+        if !options.max_runtime.is_empty() && parse_runtime(&options.max_runtime).is_none() {
+            panic!(
+                "Invalid max runtime (Example: 30m): {}",
+                options.max_runtime
+            );
+        }
+        let config_entries = if options.config_file.is_empty() {
+            Vec::new()
+        } else {
+            if !communicator.does_file_exist(&options.config_file) {
+                panic!("File doesn't exist: {}", options.config_file);
+            }
+            match communicator.get_config_file(&options.config_file) {
+                Err(e) => panic!("{:?}", e),
+                Ok(v) => get_config_entries(&v),
+            }
+        };
+        // The options on the command line take precedence over the ones
+        // in the config file.
+        let max_ages = split_labels(&options.max_age)
+            .iter()
+            .map(String::as_str)
+            .chain(
+                config_entries
+                    .iter()
+                    .filter(|(option, _)| option == "max-age")
+                    .map(|(_, value)| value.as_str()),
+            )
+            .map(|entry| MaxAge::parse(entry, now))
+            .collect();
+        let get_datasets_for = |option: &str, datasets: Vec<String>| -> Vec<String> {
+            datasets
+                .into_iter()
+                .chain(
+                    config_entries
+                        .iter()
+                        .filter(|(o, _)| o == option)
+                        .map(|(_, value)| value.to_string()),
+                )
+                .collect()
+        };
+        let include_datasets = get_datasets_for("include-dataset", options.include_datasets);
+        let exclude_datasets = get_datasets_for("exclude-dataset", options.exclude_datasets);
         Config {
-            pool: pool.to_string(),
+            pool: options.pool,
             date: cutoff_date,
-            exclude_file: exclude_file.to_string(),
-            show_queued,
-            show_excluded,
-            dry_run,
-            iteration_count,
-            no_confirm,
-            label: label.to_string(),
-            show_config,
+            fixed_date: !options.date.is_empty(),
+            exclude_file: options.exclude_file,
+            config_file: options.config_file,
+            emit_script: options.emit_script,
+            show_queued: options.show_queued,
+            show_excluded: options.show_excluded,
+            dry_run: options.dry_run,
+            iteration_count: options.iteration_count,
+            max_argument_length: options
+                .max_argument_length
+                .unwrap_or_else(|| get_default_max_argument_length(communicator)),
+            jobs: options.jobs,
+            deletion_order: options.deletion_order,
+            max_runtime: options.max_runtime,
+            max_deletions: options.max_deletions,
+            no_confirm: options.no_confirm,
+            labels: split_labels(&options.label),
+            excluded_labels: split_labels(&options.exclude_label),
+            ignore_case: options.ignore_case,
+            max_ages,
+            include_datasets,
+            exclude_datasets,
+            keep_newest: options.keep_newest,
+            max_delete_percentage: options.max_delete_percentage,
+            max_delete_count: options.max_delete_count,
+            allow_mass_deletion: options.allow_mass_deletion,
+            quarantine: options.quarantine,
+            use_ranges: options.use_ranges,
+            recursive_sets: options.recursive_sets,
+            backend: options.backend,
+            instruction_limit: options.instruction_limit,
+            memory_limit: options.memory_limit,
+            show_config: options.show_config,
+            timestamp_source: options.timestamp_source,
+            cutoff_anchor: options.cutoff_anchor,
+            time: now,
+            creation_tolerance: options.creation_tolerance,
+            strict_excludes: options.strict_excludes,
+            label_expiry: options.label_expiry,
+            protect_property: options.protect_property,
+            expires_property: options.expires_property,
         }
     }
 
@@ -95,16 +341,70 @@ impl Config {
         println!("Pool: {}", self.pool());
         println!("Cut Off Date: {}", self.date().format(SNAPSHOT_FORMAT));
         println!("Exclude File: {}", self.exclude_file());
-        println!("Label (Filter): {}", self.label());
+        println!("Config File: {}", self.config_file());
+        println!("Emit Script: {}", self.emit_script());
+        println!("Protect Property: {}", self.protect_property());
+        println!("Expires Property: {}", self.expires_property());
+        println!("Label Expiry: {}", self.label_expiry());
+        println!("Labels (Filter): {}", self.labels().join(", "));
+        println!(
+            "Excluded Labels (Filter): {}",
+            self.excluded_labels().join(", ")
+        );
+        println!("Ignore Case (Labels): {}", self.ignore_case());
+        let max_ages: Vec<String> = self.max_ages().iter().map(|m| m.to_string()).collect();
+        println!("Max Ages (By Label): {}", max_ages.join(", "));
+        println!("Include Datasets: {}", self.include_datasets().join(", "));
+        println!("Exclude Datasets: {}", self.exclude_datasets().join(", "));
+        println!(
+            "Keep Newest (Per Dataset and Label): {}",
+            self.keep_newest()
+        );
+        if let Some(percentage) = self.max_delete_percentage() {
+            println!("Max Deletions (Percentage): {}", percentage);
+        }
+        if let Some(count) = self.max_delete_count() {
+            println!("Max Deletions (Count): {}", count);
+        }
+        println!("Allow Mass Deletion: {}", self.allow_mass_deletion());
+        println!("Quarantine: {}", self.quarantine());
+        println!("Backend: {}", self.backend());
+        println!("Use Ranges: {}", self.use_ranges());
+        println!("Recursive Sets: {}", self.recursive_sets());
+        println!("Jobs: {}", self.jobs());
+        println!("Deletion Order: {}", self.deletion_order());
+        if !self.max_runtime().is_empty() {
+            println!("Budget (Runtime): {}", self.max_runtime());
+        }
+        if let Some(max_deletions) = self.max_deletions() {
+            println!("Budget (Deletions): {}", max_deletions);
+        }
+        if let Some(limit) = self.instruction_limit() {
+            println!("Instruction Limit (Channel Program): {}", limit);
+        }
+        if let Some(limit) = self.memory_limit() {
+            println!("Memory Limit (Channel Program): {}", limit);
+        }
+        println!("Timestamp Source: {}", self.timestamp_source());
+        println!("Cutoff Anchor: {}", self.cutoff_anchor());
         if self.should_show_config() {
             println!("Show Queued: {}", self.should_show_queued());
             println!("Show Excluded: {}", self.should_show_excluded());
             println!("Dry Run: {}", self.should_dry_run());
             println!("Iteration Amount (Batch): {}", self.iteration_count());
+            println!(
+                "Max Argument Length (Batch): {}",
+                self.max_argument_length()
+            );
             println!("No Confirmation: {}", self.no_confirm());
             println!("Show Config: {}", self.should_show_config());
+            println!(
+                "Creation Tolerance (Seconds): {}",
+                self.creation_tolerance()
+            );
+            println!("Strict Excludes: {}", self.strict_excludes());
         }
-        println!("");
+        println!();
     }
 
     pub fn pool(&self) -> &String {
@@ -119,6 +419,14 @@ impl Config {
         &self.exclude_file
     }
 
+    pub fn config_file(&self) -> &String {
+        &self.config_file
+    }
+
+    pub fn emit_script(&self) -> &String {
+        &self.emit_script
+    }
+
     pub fn should_show_queued(&self) -> bool {
         self.show_queued
     }
@@ -135,190 +443,1637 @@ impl Config {
         self.iteration_count
     }
 
+    pub fn max_argument_length(&self) -> usize {
+        self.max_argument_length
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    pub fn deletion_order(&self) -> &DeletionOrder {
+        &self.deletion_order
+    }
+
+    pub fn max_runtime(&self) -> &String {
+        &self.max_runtime
+    }
+
+    pub fn max_runtime_duration(&self) -> Option<std::time::Duration> {
+        parse_runtime(&self.max_runtime)
+    }
+
+    pub fn max_deletions(&self) -> Option<usize> {
+        self.max_deletions
+    }
+
+    /// Returns how the stale snapshots are sent to zfs destroy.
+    pub fn destroy_config(&self) -> DestroyConfig {
+        DestroyConfig::new(
+            self.iteration_count,
+            self.max_argument_length,
+            self.jobs,
+            self.deletion_order,
+            self.max_runtime_duration(),
+            self.max_deletions,
+        )
+    }
+
     pub fn no_confirm(&self) -> bool {
         self.no_confirm
     }
 
-    pub fn label(&self) -> &String {
-        &self.label
+    pub fn labels(&self) -> &Vec<String> {
+        &self.labels
+    }
+
+    pub fn excluded_labels(&self) -> &Vec<String> {
+        &self.excluded_labels
+    }
+
+    pub fn ignore_case(&self) -> bool {
+        self.ignore_case
+    }
+
+    pub fn max_ages(&self) -> &Vec<MaxAge> {
+        &self.max_ages
+    }
+
+    pub fn include_datasets(&self) -> &Vec<String> {
+        &self.include_datasets
+    }
+
+    pub fn exclude_datasets(&self) -> &Vec<String> {
+        &self.exclude_datasets
+    }
+
+    pub fn keep_newest(&self) -> usize {
+        self.keep_newest
+    }
+
+    pub fn max_delete_percentage(&self) -> Option<f32> {
+        self.max_delete_percentage
+    }
+
+    pub fn max_delete_count(&self) -> Option<usize> {
+        self.max_delete_count
+    }
+
+    pub fn allow_mass_deletion(&self) -> bool {
+        self.allow_mass_deletion
+    }
+
+    pub fn quarantine(&self) -> bool {
+        self.quarantine
+    }
+
+    pub fn use_ranges(&self) -> bool {
+        self.use_ranges
+    }
+
+    pub fn recursive_sets(&self) -> bool {
+        self.recursive_sets
+    }
+
+    pub fn backend(&self) -> &Backend {
+        &self.backend
+    }
+
+    pub fn instruction_limit(&self) -> Option<u64> {
+        self.instruction_limit
+    }
+
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.memory_limit
+    }
+
+    /// Returns the max age for the label. The first one that matches wins.
+    pub fn max_age_for(&self, label: &str) -> Option<&MaxAge> {
+        self.max_ages
+            .iter()
+            .find(|max_age| max_age.matches(label, self.ignore_case))
+    }
+
+    /// Returns the cutoff date for snapshots with this label, which is the
+    /// one from its max age or the cutoff date otherwise.
+    pub fn cutoff_date_for(&self, label: &str) -> &DateTime<Local> {
+        match self.max_age_for(label) {
+            Some(max_age) => max_age.cutoff_date(),
+            None => self.date(),
+        }
+    }
+
+    /// Returns the cutoff date for snapshots with this label when their age
+    /// is measured against the anchor rather than now. A cutoff date that
+    /// was given explicitly doesn't move. An anchor in the future (Clock
+    /// skew or a misnamed snapshot) is treated as now, so the cutoff date is
+    /// never later than without an anchor.
+    pub fn anchored_cutoff_date_for(
+        &self,
+        label: &str,
+        anchor: &DateTime<Local>,
+    ) -> DateTime<Local> {
+        let anchor = &(*anchor).min(self.time);
+        match self.max_age_for(label) {
+            Some(max_age) => *max_age.cutoff_date() + (*anchor - self.time),
+            None if self.fixed_date => *self.date(),
+            None => *self.date() + (*anchor - self.time),
+        }
     }
 
     pub fn should_show_config(&self) -> bool {
         self.show_config
     }
-}
 
-#[derive(PartialEq, Eq, PartialOrd, Ord)]
-pub struct Snapshot {
-    pool: String,
-    dataset: String,
-    date: DateTime<Local>,
-    label: String,
-    suffix: String,
-}
+    pub fn timestamp_source(&self) -> &TimestampSource {
+        &self.timestamp_source
+    }
 
-impl Snapshot {
-    pub fn new(pool: &str, dataset: &str, date: DateTime<Local>, label: &str) -> Snapshot {
-        let mut snapshot = Snapshot {
-            pool: pool.to_string(),
-            dataset: dataset.to_string(),
-            date,
-            label: label.to_string(),
-            suffix: String::new(),
-        };
+    pub fn cutoff_anchor(&self) -> &CutoffAnchor {
+        &self.cutoff_anchor
+    }
 
-        // Auto-generate the suffix name so we don't have to create
-        // multiple string copies later.
-        snapshot
-            .suffix
-            .push_str(snapshot.date.format(SNAPSHOT_FORMAT).to_string().as_str());
-        snapshot.suffix.push_str("-");
-        snapshot.suffix.push_str(snapshot.label.as_str());
-        snapshot
+    pub fn creation_tolerance(&self) -> i64 {
+        self.creation_tolerance
     }
 
-    pub fn is_stale(&self, cutoff_date: &DateTime<Local>) -> bool {
-        &self.date < cutoff_date
+    pub fn strict_excludes(&self) -> bool {
+        self.strict_excludes
     }
 
-    pub fn suffix(&self) -> &String {
-        &self.suffix
+    pub fn protect_property(&self) -> &str {
+        &self.protect_property
     }
 
-    pub fn pool(&self) -> &String {
-        &self.pool
+    pub fn expires_property(&self) -> &str {
+        &self.expires_property
     }
 
-    pub fn dataset(&self) -> &String {
-        &self.dataset
+    pub fn label_expiry(&self) -> bool {
+        self.label_expiry
     }
-    pub fn date(&self) -> &DateTime<Local> {
-        &self.date
+}
+
+/// How old the snapshots with a label (Which may be a pattern) can get
+/// before they are cleaned.
+///
+/// Format: HOURLY=2d
+#[derive(Debug)]
+pub struct MaxAge {
+    label: String,
+    lifetime: String,
+    cutoff_date: DateTime<Local>,
+}
+
+impl MaxAge {
+    pub fn parse(entry: &str, now: DateTime<Local>) -> MaxAge {
+        let (label, lifetime) = match entry.split_once('=') {
+            Some((label, lifetime)) => (label.trim(), lifetime.trim()),
+            None => ("", ""),
+        };
+        let lifetime_duration = match parse_lifetime(lifetime) {
+            Some(v) if !label.is_empty() => v,
+            _ => panic!("Invalid max age (Example: HOURLY=2d): {}", entry),
+        };
+        MaxAge {
+            label: label.to_string(),
+            lifetime: lifetime.to_string(),
+            cutoff_date: now - lifetime_duration,
+        }
+    }
+
+    pub fn matches(&self, label: &str, ignore_case: bool) -> bool {
+        label_matches_any(std::slice::from_ref(&self.label), label, ignore_case)
     }
 
     pub fn label(&self) -> &String {
         &self.label
     }
-}
 
-impl fmt::Display for Snapshot {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}@{}-{}",
-            self.dataset,
-            self.date.format(SNAPSHOT_FORMAT).to_string(),
-            self.label
-        )
+    pub fn lifetime(&self) -> &String {
+        &self.lifetime
+    }
+
+    pub fn cutoff_date(&self) -> &DateTime<Local> {
+        &self.cutoff_date
     }
 }
 
-impl fmt::Debug for Snapshot {
+impl fmt::Display for MaxAge {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}@{}-{}",
-            self.dataset,
-            self.date.format(SNAPSHOT_FORMAT).to_string(),
-            self.label
-        )
+        write!(f, "{}={}", self.label, self.lifetime)
     }
 }
 
-pub struct RealCommunicator;
-
-// Integration Tested Only
-impl Communicator for RealCommunicator {
-    fn get_snapshots(&self) -> SystemResult {
-        // Example: zfs list -t snapshot -H -o name -s name
-
-        let zfs_cmd = match Command::new("zfs")
-            .arg("list")
-            .arg("-t")
-            .arg("snapshot")
-            .arg("-H")
-            .arg("-o")
-            .arg("name")
-            .arg("-s")
-            .arg("name")
-            .stdout(Stdio::piped())
-            .spawn()
-        {
-            Err(e) => return Err(SystemError::SpawnProcess(e.to_string())),
-            Ok(p) => p,
-        };
+#[derive(Debug)]
+pub struct SnapshotConfig {
+    datasets: Vec<String>,
+    label: String,
+    recursive: bool,
+}
 
-        let mut results = String::new();
-        match zfs_cmd.stdout.unwrap().read_to_string(&mut results) {
-            Err(e) => Err(SystemError::ReadingFromString(e.to_string())),
-            Ok(_) => Ok(results),
+impl SnapshotConfig {
+    pub fn new(datasets: Vec<String>, label: &str, recursive: bool) -> SnapshotConfig {
+        // The label is the last '-' separated section of the snapshot name,
+        // so it can't contain one itself or it would never be cleaned. It
+        // also can't be a list of labels or a pattern.
+        if label.is_empty() || label.contains(['-', '@', ',', '*', '?']) {
+            panic!(
+                "Invalid label (It can't be empty or contain '-', '@', ',', '*' or '?'): {}",
+                label
+            );
         }
-    }
-
-    fn destroy_snapshots(&self, snapshots: String) -> SystemResult {
-        match Command::new("zfs").arg("destroy").arg(&snapshots).status() {
-            Ok(_) => Ok(snapshots),
-            Err(e) => Err(SystemError::DeleteSnapshots(e.to_string())),
+        SnapshotConfig {
+            datasets,
+            label: label.to_string(),
+            recursive,
         }
     }
 
-    fn get_excluded_snapshots(&self, exclude_file: &str) -> SystemResult {
-        let mut f = match File::open(exclude_file) {
-            Err(e) => return Err(SystemError::OpeningFile(e.to_string())),
-            Ok(v) => v,
-        };
+    pub fn print(&self) {
+        println!("Configuration");
+        println!("----------------");
+        println!("Datasets: {}", self.datasets().join(", "));
+        println!("Label: {}", self.label());
+        println!("Recursive: {}", self.recursive());
+        println!();
+    }
 
-        let mut contents = String::new();
+    pub fn datasets(&self) -> &Vec<String> {
+        &self.datasets
+    }
 
-        match f.read_to_string(&mut contents) {
-            Err(e) => Err(SystemError::ReadingFromString(e.to_string())),
-            Ok(_) => Ok(contents),
-        }
+    pub fn label(&self) -> &String {
+        &self.label
     }
-    fn does_file_exist(&self, filename: &str) -> bool {
-        Path::new(filename).exists()
+
+    pub fn recursive(&self) -> bool {
+        self.recursive
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::super::testing::utility::*;
-    use super::*;
+#[derive(Debug)]
+pub struct PinConfig {
+    pool: String,
+    snapshot: String,
+    exclude_file: String,
+    pin: Pin,
+    protect_property: String,
+}
 
-    mod snapshot {
-        use super::*;
-        #[test]
-        fn is_stale_if_old_should_return_true() {
-            let cutoff_date = Local.ymd(2020, 08, 15).and_hms(23, 54, 09);
-            let snapshot = create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT");
-            assert!(snapshot.is_stale(&cutoff_date));
+impl PinConfig {
+    pub fn new(
+        pool: &str,
+        snapshot: &str,
+        exclude_file: &str,
+        pin: Pin,
+        protect_property: &str,
+    ) -> PinConfig {
+        if !snapshot.is_empty() && parse_snapshot(snapshot).is_none() {
+            panic!(
+                "Invalid snapshot: Example: tank@2017-09-26-1111-00-CHECKPOINT: {}",
+                snapshot
+            );
         }
-        #[test]
-        fn is_stale_if_new_should_return_false() {
-            let cutoff_date = Local.ymd(2020, 08, 15).and_hms(23, 54, 09);
-            let snapshot = create_snapshot("tank/gentoo/os", "2020-08-15-2354-09", "CHECKPOINT");
-            assert_eq!(snapshot.is_stale(&cutoff_date), false);
+        let pool = match snapshot.split(['/', '@']).next() {
+            Some(v) if !snapshot.is_empty() => v,
+            _ => pool,
+        };
+        PinConfig {
+            pool: pool.to_string(),
+            snapshot: snapshot.to_string(),
+            exclude_file: exclude_file.to_string(),
+            pin,
+            protect_property: protect_property.to_string(),
         }
     }
 
-    mod config {
-        use super::*;
-        #[test]
-        fn get_config() {
-            let communicator = FakeCommunicator::new(true);
-            let date = "2099-01-01-0000-00";
-            let config = Config::new(
-                &communicator,
-                "tank",
-                date,
-                "some-file",
-                true,
-                true,
+    pub fn print(&self) {
+        println!("Configuration");
+        println!("----------------");
+        println!("Pool: {}", self.pool());
+        if !self.snapshot().is_empty() {
+            println!("Snapshot: {}", self.snapshot());
+        }
+        if self.exclude_file().is_empty() {
+            println!("Protect Property: {}", self.protect_property());
+        } else {
+            println!("Exclude File: {}", self.exclude_file());
+        }
+        println!();
+    }
+
+    pub fn pool(&self) -> &String {
+        &self.pool
+    }
+
+    pub fn snapshot(&self) -> &String {
+        &self.snapshot
+    }
+
+    pub fn exclude_file(&self) -> &String {
+        &self.exclude_file
+    }
+
+    pub fn pin(&self) -> &Pin {
+        &self.pin
+    }
+
+    pub fn protect_property(&self) -> &str {
+        &self.protect_property
+    }
+}
+
+#[derive(Debug)]
+pub struct ApplyConfig {
+    plan_file: String,
+    dry_run: bool,
+    iteration_count: u32,
+    no_confirm: bool,
+    max_plan_age: String,
+}
+
+impl ApplyConfig {
+    pub fn new<T: Communicator>(
+        communicator: &T,
+        plan_file: &str,
+        dry_run: bool,
+        iteration_count: u32,
+        no_confirm: bool,
+        max_plan_age: &str,
+    ) -> ApplyConfig {
+        if !communicator.does_file_exist(plan_file) {
+            panic!("File doesn't exist: {}", plan_file);
+        }
+        if parse_lifetime(max_plan_age).is_none() {
+            panic!("Invalid max plan age (Example: 24h): {}", max_plan_age);
+        }
+        ApplyConfig {
+            plan_file: plan_file.to_string(),
+            dry_run,
+            iteration_count,
+            no_confirm,
+            max_plan_age: max_plan_age.to_string(),
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Configuration");
+        println!("----------------");
+        println!("Plan File: {}", self.plan_file());
+        println!("Max Plan Age: {}", self.max_plan_age());
+        println!("Dry Run: {}", self.should_dry_run());
+        println!("Iteration Amount (Batch): {}", self.iteration_count());
+        println!("No Confirmation: {}", self.no_confirm());
+        println!();
+    }
+
+    pub fn plan_file(&self) -> &String {
+        &self.plan_file
+    }
+
+    pub fn should_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn iteration_count(&self) -> u32 {
+        self.iteration_count
+    }
+
+    pub fn no_confirm(&self) -> bool {
+        self.no_confirm
+    }
+
+    pub fn max_plan_age(&self) -> &String {
+        &self.max_plan_age
+    }
+
+    pub fn max_plan_duration(&self) -> Duration {
+        parse_lifetime(&self.max_plan_age).unwrap()
+    }
+}
+
+#[derive(Debug)]
+pub struct PurgeConfig {
+    pool: String,
+    grace_period: String,
+    dry_run: bool,
+    iteration_count: u32,
+    no_confirm: bool,
+}
+
+impl PurgeConfig {
+    pub fn new(
+        pool: &str,
+        grace_period: &str,
+        dry_run: bool,
+        iteration_count: u32,
+        no_confirm: bool,
+    ) -> PurgeConfig {
+        if parse_lifetime(grace_period).is_none() {
+            panic!("Invalid grace period (Example: 7d): {}", grace_period);
+        }
+        PurgeConfig {
+            pool: pool.to_string(),
+            grace_period: grace_period.to_string(),
+            dry_run,
+            iteration_count,
+            no_confirm,
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Configuration");
+        println!("----------------");
+        println!("Pool: {}", self.pool());
+        println!("Grace Period: {}", self.grace_period());
+        println!("Dry Run: {}", self.should_dry_run());
+        println!("Iteration Amount (Batch): {}", self.iteration_count());
+        println!("No Confirmation: {}", self.no_confirm());
+        println!();
+    }
+
+    pub fn pool(&self) -> &String {
+        &self.pool
+    }
+
+    pub fn grace_period(&self) -> &String {
+        &self.grace_period
+    }
+
+    pub fn grace_duration(&self) -> Duration {
+        parse_lifetime(&self.grace_period).unwrap()
+    }
+
+    pub fn should_dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    pub fn iteration_count(&self) -> u32 {
+        self.iteration_count
+    }
+
+    pub fn no_confirm(&self) -> bool {
+        self.no_confirm
+    }
+}
+
+/// How the snapshots are batched and sent to zfs destroy.
+#[derive(Debug)]
+pub struct DestroyConfig {
+    iteration_count: u32,
+    max_argument_length: usize,
+    jobs: usize,
+    deletion_order: DeletionOrder,
+    max_runtime: Option<std::time::Duration>,
+    max_deletions: Option<usize>,
+}
+
+impl DestroyConfig {
+    pub fn new(
+        iteration_count: u32,
+        max_argument_length: usize,
+        jobs: usize,
+        deletion_order: DeletionOrder,
+        max_runtime: Option<std::time::Duration>,
+        max_deletions: Option<usize>,
+    ) -> DestroyConfig {
+        DestroyConfig {
+            iteration_count,
+            max_argument_length,
+            jobs,
+            deletion_order,
+            max_runtime,
+            max_deletions,
+        }
+    }
+
+    pub fn iteration_count(&self) -> u32 {
+        self.iteration_count
+    }
+
+    pub fn max_argument_length(&self) -> usize {
+        self.max_argument_length
+    }
+
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+
+    pub fn deletion_order(&self) -> &DeletionOrder {
+        &self.deletion_order
+    }
+
+    pub fn max_runtime(&self) -> Option<std::time::Duration> {
+        self.max_runtime
+    }
+
+    pub fn max_deletions(&self) -> Option<usize> {
+        self.max_deletions
+    }
+}
+
+/// A saved list of the snapshots to delete, so that it can be reviewed
+/// before it's applied. The hash covers everything else in the plan.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Plan {
+    pool: String,
+    created: DateTime<Local>,
+    exclude_file: String,
+    protect_property: String,
+    snapshots: Vec<String>,
+    hash: String,
+}
+
+impl Plan {
+    /// Creates a plan. The exclude file and protect property are kept so
+    /// that the snapshots can be checked again when the plan is applied.
+    pub fn new(
+        pool: &str,
+        created: DateTime<Local>,
+        exclude_file: &str,
+        protect_property: &str,
+        snapshots: Vec<String>,
+    ) -> Plan {
+        let hash = Plan::get_hash(
+            &[pool, &created.to_rfc3339(), exclude_file, protect_property],
+            &snapshots,
+        );
+        Plan {
+            pool: pool.to_string(),
+            created,
+            exclude_file: exclude_file.to_string(),
+            protect_property: protect_property.to_string(),
+            snapshots,
+            hash,
+        }
+    }
+
+    /// Parses a plan that was written by to_json. Returns the reason if it
+    /// failed, or if the plan was changed after it was written.
+    pub fn from_json(contents: &str) -> Result<Plan, String> {
+        let get_field = |name: &str| -> Result<String, String> {
+            let field = Regex::new(&format!(r#""{}"\s*:\s*"((?:[^"\\]|\\.)*)""#, name)).unwrap();
+            match field.captures(contents) {
+                Some(captures) => Ok(unescape_json(&captures[1])),
+                None => Err(format!("Missing field '{}'", name)),
+            }
+        };
+        let pool = get_field("pool")?;
+        let created = match DateTime::parse_from_rfc3339(&get_field("created")?) {
+            Ok(v) => v.with_timezone(&Local),
+            Err(_) => return Err("Invalid creation time".to_string()),
+        };
+        let exclude_file = get_field("exclude_file")?;
+        let protect_property = get_field("protect_property")?;
+        let hash = get_field("hash")?;
+
+        let list = Regex::new(r#"(?s)"snapshots"\s*:\s*\[(.*?)\]"#).unwrap();
+        let list = match list.captures(contents) {
+            Some(captures) => captures[1].to_string(),
+            None => return Err("Missing field 'snapshots'".to_string()),
+        };
+        let item = Regex::new(r#""((?:[^"\\]|\\.)*)""#).unwrap();
+        let snapshots: Vec<String> = item
+            .captures_iter(&list)
+            .map(|captures| unescape_json(&captures[1]))
+            .collect();
+
+        let plan = Plan::new(&pool, created, &exclude_file, &protect_property, snapshots);
+        if plan.hash != hash {
+            return Err("The hash doesn't match the contents of the plan".to_string());
+        }
+        Ok(plan)
+    }
+
+    pub fn to_json(&self) -> String {
+        let snapshots: Vec<String> = self
+            .snapshots
+            .iter()
+            .map(|snapshot| format!("    \"{}\"", escape_json(snapshot)))
+            .collect();
+        format!(
+            "{{\n  \"pool\": \"{}\",\n  \"created\": \"{}\",\n  \"exclude_file\": \"{}\",\n  \"protect_property\": \"{}\",\n  \"hash\": \"{}\",\n  \"snapshots\": [\n{}\n  ]\n}}\n",
+            escape_json(&self.pool),
+            self.created.to_rfc3339(),
+            escape_json(&self.exclude_file),
+            escape_json(&self.protect_property),
+            self.hash,
+            snapshots.join(",\n")
+        )
+    }
+
+    /// A 64-bit FNV-1a hash, which (Unlike the standard library's hasher)
+    /// doesn't change between versions of Rust.
+    fn get_hash(fields: &[&str], snapshots: &[String]) -> String {
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        let contents = format!("{}\n{}", fields.join("\n"), snapshots.join("\n"));
+        for byte in contents.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        format!("fnv1a64:{:016x}", hash)
+    }
+
+    pub fn pool(&self) -> &String {
+        &self.pool
+    }
+
+    pub fn created(&self) -> &DateTime<Local> {
+        &self.created
+    }
+
+    pub fn exclude_file(&self) -> &String {
+        &self.exclude_file
+    }
+
+    pub fn protect_property(&self) -> &String {
+        &self.protect_property
+    }
+
+    pub fn snapshots(&self) -> &Vec<String> {
+        &self.snapshots
+    }
+
+    pub fn hash(&self) -> &String {
+        &self.hash
+    }
+}
+
+fn escape_json(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+fn unescape_json(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => unescaped.extend(chars.next()),
+            _ => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// The details of a pin, which protects snapshots from removal. They follow
+/// the snapshot (or pattern) in the exclude file, or the 'on' value of the
+/// protection property. A pin with an expiry date protects its snapshots
+/// up to and including that day.
+///
+/// Format: owner=jon until=2020-12-31 # Before upgrading to 13.0
+#[derive(Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pin {
+    owner: String,
+    reason: String,
+    until: Option<NaiveDate>,
+}
+
+impl Pin {
+    pub fn new(owner: &str, reason: &str, until: &str) -> Pin {
+        if owner.contains(char::is_whitespace) {
+            panic!("Invalid owner (It can't contain spaces): {}", owner);
+        }
+        let until = match until {
+            "" => None,
+            _ => match NaiveDate::parse_from_str(until, PIN_DATE_FORMAT) {
+                Ok(v) => Some(v),
+                Err(_) => panic!("Invalid expiry date (Example: 2020-12-31): {}", until),
+            },
+        };
+        Pin {
+            owner: owner.to_string(),
+            reason: reason.trim().to_string(),
+            until,
+        }
+    }
+
+    /// Parses the details of a pin. Returns the reason if it failed.
+    pub fn parse(details: &str) -> Result<Pin, String> {
+        let mut pin = Pin::default();
+        let (fields, reason) = match details.find('#') {
+            Some(index) => (&details[..index], &details[index + 1..]),
+            None => (details, ""),
+        };
+
+        for field in fields.split_whitespace() {
+            match field.split_once('=') {
+                Some(("owner", value)) => pin.owner = value.to_string(),
+                Some(("until", value)) => match NaiveDate::parse_from_str(value, PIN_DATE_FORMAT) {
+                    Ok(v) => pin.until = Some(v),
+                    Err(_) => return Err(format!("Invalid expiry date '{}'", value)),
+                },
+                _ => return Err(format!("Unknown field '{}'", field)),
+            }
+        }
+        pin.reason = reason.trim().to_string();
+        Ok(pin)
+    }
+
+    pub fn owner(&self) -> &String {
+        &self.owner
+    }
+
+    pub fn reason(&self) -> &String {
+        &self.reason
+    }
+
+    pub fn until(&self) -> Option<NaiveDate> {
+        self.until
+    }
+
+    pub fn is_expired(&self, today: NaiveDate) -> bool {
+        self.until.is_some_and(|until| until < today)
+    }
+
+    /// Returns the amount of days (Including today) this pin still protects
+    /// its snapshots for, or None if it never expires.
+    pub fn days_left(&self, today: NaiveDate) -> Option<i64> {
+        self.until
+            .map(|until| (until.signed_duration_since(today).num_days() + 1).max(0))
+    }
+}
+
+impl fmt::Display for Pin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut details: Vec<String> = Vec::new();
+        if !self.owner.is_empty() {
+            details.push(format!("owner={}", self.owner));
+        }
+        if let Some(until) = self.until {
+            details.push(format!("until={}", until.format(PIN_DATE_FORMAT)));
+        }
+        if !self.reason.is_empty() {
+            details.push(format!("# {}", self.reason));
+        }
+        write!(f, "{}", details.join(" "))
+    }
+}
+
+/// An entry in the exclude file.
+#[derive(Debug)]
+pub struct Exclusion {
+    pattern: Pattern,
+    pin: Pin,
+}
+
+impl Exclusion {
+    pub fn new(pattern: Pattern, pin: Pin) -> Exclusion {
+        Exclusion { pattern, pin }
+    }
+
+    pub fn matches(&self, name: &str) -> bool {
+        self.pattern.matches(name)
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    pub fn pin(&self) -> &Pin {
+        &self.pin
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Snapshot {
+    pool: String,
+    dataset: String,
+    date: DateTime<Local>,
+    label: String,
+    suffix: String,
+    protection: Option<Pin>,
+    expiry: Option<DateTime<Local>>,
+    trashed: Option<DateTime<Local>>,
+}
+
+impl Snapshot {
+    pub fn new(pool: &str, dataset: &str, date: DateTime<Local>, label: &str) -> Snapshot {
+        let mut snapshot = Snapshot {
+            pool: pool.to_string(),
+            dataset: dataset.to_string(),
+            date,
+            label: label.to_string(),
+            suffix: String::new(),
+            protection: None,
+            expiry: None,
+            trashed: None,
+        };
+
+        // Auto-generate the suffix name so we don't have to create
+        // multiple string copies later.
+        snapshot
+            .suffix
+            .push_str(snapshot.date.format(SNAPSHOT_FORMAT).to_string().as_str());
+        snapshot.suffix.push('-');
+        snapshot.suffix.push_str(snapshot.label.as_str());
+        snapshot
+    }
+
+    /// Creates a snapshot whose timestamp comes from its ZFS creation
+    /// property rather than from its name. The name is kept as is since
+    /// it may not carry a date at all.
+    pub fn from_creation(
+        pool: &str,
+        dataset: &str,
+        name: &str,
+        label: &str,
+        creation: DateTime<Local>,
+    ) -> Snapshot {
+        Snapshot {
+            pool: pool.to_string(),
+            dataset: dataset.to_string(),
+            date: creation,
+            label: label.to_string(),
+            suffix: name.to_string(),
+            protection: None,
+            expiry: None,
+            trashed: None,
+        }
+    }
+
+    pub fn is_stale(&self, cutoff_date: &DateTime<Local>) -> bool {
+        &self.date < cutoff_date
+    }
+
+    /// Checks if the snapshot is past the expiry it was given when it was
+    /// created. Snapshots without one never expire.
+    pub fn is_expired(&self, now: &DateTime<Local>) -> bool {
+        self.expiry.is_some_and(|expiry| &expiry <= now)
+    }
+
+    pub fn suffix(&self) -> &String {
+        &self.suffix
+    }
+
+    pub fn pool(&self) -> &String {
+        &self.pool
+    }
+
+    pub fn dataset(&self) -> &String {
+        &self.dataset
+    }
+    pub fn date(&self) -> &DateTime<Local> {
+        &self.date
+    }
+
+    pub fn label(&self) -> &String {
+        &self.label
+    }
+
+    pub fn is_protected(&self) -> bool {
+        self.protection.is_some()
+    }
+
+    pub fn protection(&self) -> &Option<Pin> {
+        &self.protection
+    }
+
+    pub fn set_protection(&mut self, protection: Option<Pin>) {
+        self.protection = protection;
+    }
+
+    pub fn expiry(&self) -> Option<DateTime<Local>> {
+        self.expiry
+    }
+
+    pub fn set_expiry(&mut self, expiry: Option<DateTime<Local>>) {
+        self.expiry = expiry;
+    }
+
+    pub fn is_trashed(&self) -> bool {
+        self.trashed.is_some()
+    }
+
+    pub fn trashed(&self) -> Option<DateTime<Local>> {
+        self.trashed
+    }
+
+    /// Marks the snapshot as moved to the trash at the given time. The
+    /// trash label is added to its name.
+    pub fn set_trashed(&mut self, trashed: DateTime<Local>) {
+        self.suffix.push_str(&Snapshot::get_trash_label(trashed));
+        self.trashed = Some(trashed);
+    }
+
+    /// Returns the name the snapshot gets when it's moved to the trash.
+    /// Example: tank@2020-08-12-1237-49-CHECKPOINT-TRASH-2020-08-23-1023-17
+    pub fn trash_name(&self, time: DateTime<Local>) -> String {
+        format!("{}{}", self, Snapshot::get_trash_label(time))
+    }
+
+    fn get_trash_label(time: DateTime<Local>) -> String {
+        format!("{}{}", TRASH_MARKER, time.format(SNAPSHOT_FORMAT))
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.dataset, self.suffix)
+    }
+}
+
+impl fmt::Debug for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}@{}", self.dataset, self.suffix)
+    }
+}
+
+pub struct RealCommunicator;
+
+// Integration Tested Only
+impl Communicator for RealCommunicator {
+    fn get_snapshots(&self, properties: &[&str]) -> SystemResult {
+        // Example: zfs list -t snapshot -H -p -o name,creation,honeydew:protect,honeydew:expires -s name
+
+        let mut columns = vec!["name", "creation"];
+        columns.extend_from_slice(properties);
+
+        let zfs_cmd = match Command::new("zfs")
+            .arg("list")
+            .arg("-t")
+            .arg("snapshot")
+            .arg("-H")
+            .arg("-p")
+            .arg("-o")
+            .arg(columns.join(","))
+            .arg("-s")
+            .arg("name")
+            .stdout(Stdio::piped())
+            .spawn()
+        {
+            Err(e) => return Err(SystemError::SpawnProcess(e.to_string())),
+            Ok(p) => p,
+        };
+
+        let mut results = String::new();
+        match zfs_cmd.stdout.unwrap().read_to_string(&mut results) {
+            Err(e) => Err(SystemError::ReadingFromString(e.to_string())),
+            Ok(_) => Ok(results),
+        }
+    }
+
+    fn get_snapshot_order(&self) -> SystemResult {
+        // Example: zfs list -t snapshot -H -o name -s createtxg
+
+        match Command::new("zfs")
+            .arg("list")
+            .arg("-t")
+            .arg("snapshot")
+            .arg("-H")
+            .arg("-o")
+            .arg("name")
+            .arg("-s")
+            .arg("createtxg")
+            .output()
+        {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            }
+            Ok(output) => Err(SystemError::ReadingFromString(output.status.to_string())),
+            Err(e) => Err(SystemError::SpawnProcess(e.to_string())),
+        }
+    }
+
+    fn destroy_snapshots(&self, snapshots: String) -> SystemResult {
+        match Command::new("zfs").arg("destroy").arg(&snapshots).status() {
+            Ok(status) if status.success() => Ok(snapshots),
+            Ok(status) => Err(SystemError::DeleteSnapshots(status.to_string())),
+            Err(e) => Err(SystemError::DeleteSnapshots(e.to_string())),
+        }
+    }
+
+    fn run_channel_program(
+        &self,
+        pool: &str,
+        program: &str,
+        instruction_limit: Option<u64>,
+        memory_limit: Option<u64>,
+    ) -> SystemResult {
+        // Example: zfs program -t 10000000 -m 10485760 tank -
+        // The program is piped through stdin, so it never touches the disk.
+
+        let mut zfs_cmd = Command::new("zfs");
+        zfs_cmd.arg("program");
+        if let Some(limit) = instruction_limit {
+            zfs_cmd.arg("-t").arg(limit.to_string());
+        }
+        if let Some(limit) = memory_limit {
+            zfs_cmd.arg("-m").arg(limit.to_string());
+        }
+        let mut child = match zfs_cmd
+            .arg(pool)
+            .arg("-")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+        {
+            Err(e) => return Err(SystemError::SpawnProcess(e.to_string())),
+            Ok(p) => p,
+        };
+        // Dropping stdin closes it, so zfs sees the end of the program.
+        if let Some(mut stdin) = child.stdin.take() {
+            if let Err(e) = stdin.write_all(program.as_bytes()) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(SystemError::ChannelProgram(e.to_string()));
+            }
+        }
+
+        match child.wait_with_output() {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            }
+            Ok(output) => Err(SystemError::ChannelProgram(
+                String::from_utf8_lossy(&output.stderr).to_string(),
+            )),
+            Err(e) => Err(SystemError::ChannelProgram(e.to_string())),
+        }
+    }
+
+    fn rename_snapshot(&self, snapshot: &str, new_name: &str) -> SystemResult {
+        // Example: zfs rename tank@2020-08-12-1237-49-CHECKPOINT tank@2020-08-12-1237-49-CHECKPOINT-TRASH-2020-08-23-1023-17
+
+        match Command::new("zfs")
+            .arg("rename")
+            .arg(snapshot)
+            .arg(new_name)
+            .status()
+        {
+            Ok(status) if status.success() => Ok(new_name.to_string()),
+            Ok(status) => Err(SystemError::RenameSnapshot(status.to_string())),
+            Err(e) => Err(SystemError::RenameSnapshot(e.to_string())),
+        }
+    }
+
+    fn destroy_snapshot_recursively(&self, snapshot: &str) -> SystemResult {
+        // Example: zfs destroy -r tank@2020-08-23-1023-17-ANIMALS
+
+        match Command::new("zfs")
+            .arg("destroy")
+            .arg("-r")
+            .arg(snapshot)
+            .status()
+        {
+            Ok(status) if status.success() => Ok(snapshot.to_string()),
+            Ok(status) => Err(SystemError::DeleteSnapshots(status.to_string())),
+            Err(e) => Err(SystemError::DeleteSnapshots(e.to_string())),
+        }
+    }
+
+    fn create_snapshots(&self, snapshots: &[String], recursive: bool) -> SystemResult {
+        // Example: zfs snapshot -r tank@2020-08-23-1023-17-ANIMALS
+
+        let mut zfs_cmd = Command::new("zfs");
+        zfs_cmd.arg("snapshot");
+        if recursive {
+            zfs_cmd.arg("-r");
+        }
+
+        match zfs_cmd.args(snapshots).status() {
+            Ok(status) if status.success() => Ok(snapshots.join(" ")),
+            Ok(status) => Err(SystemError::CreateSnapshots(status.to_string())),
+            Err(e) => Err(SystemError::CreateSnapshots(e.to_string())),
+        }
+    }
+
+    fn set_property(&self, snapshot: &str, property: &str, value: &str) -> SystemResult {
+        // Example: zfs set honeydew:protect=on tank@2020-08-23-1023-17-ANIMALS
+
+        let assignment = format!("{}={}", property, value);
+        match Command::new("zfs")
+            .arg("set")
+            .arg(&assignment)
+            .arg(snapshot)
+            .status()
+        {
+            Ok(status) if status.success() => Ok(assignment),
+            Ok(status) => Err(SystemError::SetProperty(status.to_string())),
+            Err(e) => Err(SystemError::SetProperty(e.to_string())),
+        }
+    }
+
+    fn inherit_property(&self, snapshot: &str, property: &str) -> SystemResult {
+        // Example: zfs inherit honeydew:protect tank@2020-08-23-1023-17-ANIMALS
+
+        match Command::new("zfs")
+            .arg("inherit")
+            .arg(property)
+            .arg(snapshot)
+            .status()
+        {
+            Ok(status) if status.success() => Ok(property.to_string()),
+            Ok(status) => Err(SystemError::SetProperty(status.to_string())),
+            Err(e) => Err(SystemError::SetProperty(e.to_string())),
+        }
+    }
+
+    fn get_excluded_snapshots(&self, exclude_file: &str) -> SystemResult {
+        let mut f = match File::open(exclude_file) {
+            Err(e) => return Err(SystemError::OpeningFile(e.to_string())),
+            Ok(v) => v,
+        };
+
+        let mut contents = String::new();
+
+        match f.read_to_string(&mut contents) {
+            Err(e) => Err(SystemError::ReadingFromString(e.to_string())),
+            Ok(_) => Ok(contents),
+        }
+    }
+    fn get_config_file(&self, config_file: &str) -> SystemResult {
+        self.get_excluded_snapshots(config_file)
+    }
+    fn get_plan(&self, plan_file: &str) -> SystemResult {
+        self.get_excluded_snapshots(plan_file)
+    }
+    fn write_plan(&self, plan_file: &str, contents: &str) -> SystemResult {
+        self.write_excluded_snapshots(plan_file, contents)
+    }
+    fn write_script(&self, script_file: &str, contents: &str) -> SystemResult {
+        let results = self.write_excluded_snapshots(script_file, contents)?;
+        match std::fs::set_permissions(script_file, std::fs::Permissions::from_mode(0o755)) {
+            Err(e) => Err(SystemError::WritingToFile(e.to_string())),
+            Ok(_) => Ok(results),
+        }
+    }
+    fn write_excluded_snapshots(&self, exclude_file: &str, contents: &str) -> SystemResult {
+        let mut f = match File::create(exclude_file) {
+            Err(e) => return Err(SystemError::OpeningFile(e.to_string())),
+            Ok(v) => v,
+        };
+
+        match f.write_all(contents.as_bytes()) {
+            Err(e) => Err(SystemError::WritingToFile(e.to_string())),
+            Ok(_) => Ok(contents.to_string()),
+        }
+    }
+
+    fn get_arg_max(&self) -> SystemResult {
+        // Example: getconf ARG_MAX
+
+        match Command::new("getconf").arg("ARG_MAX").output() {
+            Ok(output) if output.status.success() => {
+                Ok(String::from_utf8_lossy(&output.stdout).to_string())
+            }
+            Ok(output) => Err(SystemError::SpawnProcess(output.status.to_string())),
+            Err(e) => Err(SystemError::SpawnProcess(e.to_string())),
+        }
+    }
+
+    fn does_file_exist(&self, filename: &str) -> bool {
+        Path::new(filename).exists()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::testing::utility::*;
+    use super::*;
+
+    mod snapshot {
+        use super::*;
+        #[test]
+        fn is_stale_if_old_should_return_true() {
+            let cutoff_date = Local.ymd(2020, 8, 15).and_hms(23, 54, 9);
+            let snapshot = create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT");
+            assert!(snapshot.is_stale(&cutoff_date));
+        }
+        #[test]
+        fn is_stale_if_new_should_return_false() {
+            let cutoff_date = Local.ymd(2020, 8, 15).and_hms(23, 54, 9);
+            let snapshot = create_snapshot("tank/gentoo/os", "2020-08-15-2354-09", "CHECKPOINT");
+            assert!(!snapshot.is_stale(&cutoff_date));
+        }
+        #[test]
+        fn from_creation_should_keep_name() {
+            let creation = Local.ymd(2020, 8, 15).and_hms(23, 54, 9);
+            let snapshot =
+                Snapshot::from_creation("tank", "tank/gentoo/os", "manual", "", creation);
+            assert_eq!(snapshot.to_string(), "tank/gentoo/os@manual");
+            assert_eq!(snapshot.date(), &creation);
+        }
+        #[test]
+        fn set_trashed_should_add_trash_label() {
+            let time = Local.ymd(2020, 8, 23).and_hms(10, 23, 17);
+            let mut snapshot =
+                create_snapshot("tank/gentoo/os", "2020-07-13-2354-09", "CHECKPOINT");
+            let trash_name = snapshot.trash_name(time);
+            snapshot.set_trashed(time);
+            assert_eq!(
+                trash_name,
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT-TRASH-2020-08-23-1023-17"
+            );
+            assert_eq!(snapshot.to_string(), trash_name);
+            assert_eq!(snapshot.trashed(), Some(time));
+        }
+    }
+
+    mod snapshot_config {
+        use super::*;
+        #[test]
+        fn get_snapshot_config() {
+            let config = SnapshotConfig::new(vec!["tank".to_string()], "ANIMALS", true);
+            assert_eq!(config.datasets(), &vec!["tank".to_string()]);
+            assert_eq!(config.label(), "ANIMALS");
+            assert!(config.recursive());
+        }
+        #[test]
+        #[should_panic]
+        fn snapshot_config_if_label_has_dash_should_panic() {
+            SnapshotConfig::new(vec!["tank".to_string()], "PRE-UPGRADE", false);
+        }
+        #[test]
+        #[should_panic]
+        fn snapshot_config_if_label_is_a_list_should_panic() {
+            SnapshotConfig::new(vec!["tank".to_string()], "HOURLY,DAILY", false);
+        }
+    }
+
+    mod pin {
+        use super::*;
+        #[test]
+        fn parse_should_read_owner_and_reason() {
+            let pin = Pin::parse(" owner=jon # Before upgrading # to 13.0").unwrap();
+            assert_eq!(pin.owner(), "jon");
+            assert_eq!(pin.reason(), "Before upgrading # to 13.0");
+            assert_eq!(Pin::parse("").unwrap(), Pin::default());
+            assert!(Pin::parse("owner").is_err());
+        }
+        #[test]
+        fn display_should_round_trip() {
+            let pin = Pin::new("jon", "Before upgrading", "");
+            assert_eq!(pin.to_string(), "owner=jon # Before upgrading");
+            assert_eq!(Pin::parse(&pin.to_string()).unwrap(), pin);
+
+            let pin = Pin::new("jon", "Before upgrading", "2020-12-31");
+            assert_eq!(
+                pin.to_string(),
+                "owner=jon until=2020-12-31 # Before upgrading"
+            );
+            assert_eq!(Pin::parse(&pin.to_string()).unwrap(), pin);
+        }
+        #[test]
+        fn parse_should_read_until() {
+            let pin = Pin::parse("until=2020-12-31").unwrap();
+            assert_eq!(pin.until(), Some(NaiveDate::from_ymd(2020, 12, 31)));
+            assert!(Pin::parse("until=2020-12-32").is_err());
+            assert!(Pin::parse("until=tomorrow").is_err());
+        }
+        #[test]
+        fn pin_should_expire_after_until() {
+            let pin = Pin::new("", "", "2020-12-31");
+            assert!(!pin.is_expired(NaiveDate::from_ymd(2020, 12, 30)));
+            assert!(!pin.is_expired(NaiveDate::from_ymd(2020, 12, 31)));
+            assert!(pin.is_expired(NaiveDate::from_ymd(2021, 1, 1)));
+            assert_eq!(pin.days_left(NaiveDate::from_ymd(2020, 12, 30)), Some(2));
+            assert_eq!(pin.days_left(NaiveDate::from_ymd(2021, 1, 5)), Some(0));
+            assert!(!Pin::default().is_expired(NaiveDate::from_ymd(2099, 1, 1)));
+            assert_eq!(
+                Pin::default().days_left(NaiveDate::from_ymd(2099, 1, 1)),
+                None
+            );
+        }
+        #[test]
+        #[should_panic(expected = "Invalid expiry date")]
+        fn new_should_panic_for_invalid_until() {
+            Pin::new("jon", "", "12/31/2020");
+        }
+    }
+
+    mod pin_config {
+        use super::*;
+        #[test]
+        fn get_pin_config() {
+            let config = PinConfig::new(
+                "",
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT",
+                "some-file",
+                Pin::new("jon", "Upgrade", ""),
+                "honeydew:protect",
+            );
+            assert_eq!(config.pool(), "tank");
+            assert_eq!(
+                config.snapshot(),
+                "tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT"
+            );
+            assert_eq!(config.exclude_file(), "some-file");
+            assert_eq!(config.pin(), &Pin::new("jon", "Upgrade", ""));
+            assert_eq!(config.protect_property(), "honeydew:protect");
+        }
+        #[test]
+        #[should_panic]
+        fn pin_config_if_snapshot_is_invalid_should_panic() {
+            PinConfig::new("", "tank@lol", "", Pin::default(), "honeydew:protect");
+        }
+    }
+
+    mod plan {
+        use super::*;
+        fn get_plan() -> Plan {
+            Plan::new(
+                "tank",
+                Local.ymd(2020, 8, 23).and_hms(10, 23, 17),
+                "exclude.txt",
+                "honeydew:protect",
+                vec![
+                    "tank@2020-07-13-2354-09-CHECKPOINT".to_string(),
+                    "tank/gentoo/os@manual \"quoted\"".to_string(),
+                ],
+            )
+        }
+        #[test]
+        fn json_should_round_trip() {
+            let plan = get_plan();
+            let json = plan.to_json();
+            assert!(json.contains("\"hash\": \"fnv1a64:"));
+            assert_eq!(Plan::from_json(&json).unwrap(), plan);
+
+            let empty_plan = Plan::new("tank", *plan.created(), "", "honeydew:protect", Vec::new());
+            assert_eq!(Plan::from_json(&empty_plan.to_json()).unwrap(), empty_plan);
+        }
+        #[test]
+        fn from_json_should_detect_changes() {
+            let json = get_plan().to_json();
+            let changed = json.replace("2020-07-13", "2020-07-14");
+            assert_eq!(
+                Plan::from_json(&changed),
+                Err("The hash doesn't match the contents of the plan".to_string())
+            );
+            let changed = json.replace("exclude.txt", "other.txt");
+            assert_eq!(
+                Plan::from_json(&changed),
+                Err("The hash doesn't match the contents of the plan".to_string())
+            );
+            assert!(Plan::from_json("{}").is_err());
+        }
+    }
+
+    mod apply_config {
+        use super::*;
+        #[test]
+        fn get_apply_config() {
+            let config = ApplyConfig::new(
+                &FakeCommunicator::new(true),
+                "plan.json",
+                true,
+                20,
                 true,
-                59,
+                "2d",
+            );
+            assert_eq!(config.plan_file(), "plan.json");
+            assert!(config.should_dry_run());
+            assert_eq!(config.iteration_count(), 20);
+            assert!(config.no_confirm());
+            assert_eq!(config.max_plan_duration(), Duration::days(2));
+        }
+        #[test]
+        #[should_panic(expected = "File doesn't exist")]
+        fn apply_config_without_plan_should_panic() {
+            ApplyConfig::new(
+                &FakeCommunicator::new(false),
+                "plan.json",
+                true,
+                20,
+                true,
+                "2d",
+            );
+        }
+        #[test]
+        #[should_panic(expected = "Invalid max plan age")]
+        fn apply_config_with_invalid_age_should_panic() {
+            ApplyConfig::new(
+                &FakeCommunicator::new(true),
+                "plan.json",
                 true,
-                "ANIMALS",
+                20,
                 true,
+                "soon",
+            );
+        }
+    }
+
+    mod purge_config {
+        use super::*;
+        #[test]
+        fn get_purge_config() {
+            let config = PurgeConfig::new("tank", "2w", true, 20, true);
+            assert_eq!(config.pool(), "tank");
+            assert_eq!(config.grace_duration(), Duration::weeks(2));
+            assert!(config.should_dry_run());
+            assert_eq!(config.iteration_count(), 20);
+            assert!(config.no_confirm());
+        }
+        #[test]
+        #[should_panic(expected = "Invalid grace period")]
+        fn purge_config_with_invalid_grace_period_should_panic() {
+            PurgeConfig::new("tank", "a while", false, 20, false);
+        }
+    }
+
+    mod max_age {
+        use super::*;
+        #[test]
+        fn parse_should_compute_cutoff_date() {
+            let now = Local.ymd(2020, 8, 23).and_hms(10, 23, 17);
+            let max_age = MaxAge::parse("PRE* = 2w", now);
+            assert_eq!(max_age.label(), "PRE*");
+            assert_eq!(max_age.lifetime(), "2w");
+            assert_eq!(
+                max_age.cutoff_date(),
+                &Local.ymd(2020, 8, 9).and_hms(10, 23, 17)
+            );
+            assert!(max_age.matches("PREUPGRADE", false));
+            assert!(max_age.matches("preupgrade", true));
+            assert!(!max_age.matches("preupgrade", false));
+            assert!(!max_age.matches("HOURLY", true));
+        }
+        #[test]
+        #[should_panic(expected = "Invalid max age")]
+        fn parse_should_panic_without_lifetime() {
+            MaxAge::parse("HOURLY", Local::now());
+        }
+    }
+
+    mod config {
+        use super::*;
+        #[test]
+        #[should_panic(expected = "Invalid percentage")]
+        fn config_with_invalid_percentage_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    max_delete_percentage: Some(150.0),
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "Invalid amount of jobs")]
+        fn config_without_jobs_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    jobs: 0,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "Invalid max runtime")]
+        fn config_with_invalid_max_runtime_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    max_runtime: "30d".to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "The channel program backend can't be used with --quarantine")]
+        fn config_with_channel_program_and_quarantine_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    backend: Backend::ChannelProgram,
+                    quarantine: true,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(
+            expected = "The channel program backend can't be used with --quarantine or --recursive-sets"
+        )]
+        fn config_with_channel_program_and_recursive_sets_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    backend: Backend::ChannelProgram,
+                    recursive_sets: true,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "--jobs can't be used with --recursive-sets")]
+        fn config_with_recursive_sets_and_jobs_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    recursive_sets: true,
+                    jobs: 4,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "--ranges can't be used with --backend channel-program")]
+        fn config_with_channel_program_and_ranges_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    backend: Backend::ChannelProgram,
+                    use_ranges: true,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "--per-iteration can't be used with --recursive-sets")]
+        fn config_with_recursive_sets_and_per_iteration_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    recursive_sets: true,
+                    iteration_count: 10,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "A channel program can't be stopped")]
+        fn config_with_channel_program_and_max_runtime_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    backend: Backend::ChannelProgram,
+                    max_runtime: "30m".to_string(),
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        #[should_panic(expected = "A script can't quarantine snapshots")]
+        fn config_with_script_and_quarantine_should_panic() {
+            Config::new(
+                &FakeCommunicator::new(true),
+                Options {
+                    pool: "tank".to_string(),
+                    emit_script: "out.sh".to_string(),
+                    quarantine: true,
+                    ..Default::default()
+                },
+            );
+        }
+        #[test]
+        fn get_config() {
+            let communicator = FakeCommunicator::new(true);
+            let date = "2099-01-01-0000-00";
+            let config = Config::new(
+                &communicator,
+                Options {
+                    pool: "tank".to_string(),
+                    date: date.to_string(),
+                    exclude_file: "some-file".to_string(),
+                    config_file: "honeydew.conf".to_string(),
+                    emit_script: "out.sh".to_string(),
+                    show_queued: true,
+                    show_excluded: true,
+                    dry_run: true,
+                    iteration_count: 59,
+                    max_argument_length: Some(4096),
+                    jobs: 4,
+                    deletion_order: DeletionOrder::Dataset,
+                    max_runtime: "30m".to_string(),
+                    max_deletions: Some(500),
+                    no_confirm: true,
+                    label: "ANIMALS, pre*".to_string(),
+                    exclude_label: "MONTHLY".to_string(),
+                    ignore_case: true,
+                    max_age: "HOURLY=2d, daily=7d".to_string(),
+                    include_datasets: vec!["tank/*".to_string()],
+                    exclude_datasets: vec!["tank/poudriere/*".to_string()],
+                    keep_newest: 3,
+                    max_delete_percentage: Some(25.0),
+                    max_delete_count: Some(1000),
+                    allow_mass_deletion: true,
+                    quarantine: false,
+                    use_ranges: true,
+                    recursive_sets: false,
+                    backend: Backend::Batched,
+                    instruction_limit: Some(20_000_000),
+                    memory_limit: Some(100_000_000),
+                    show_config: true,
+                    timestamp_source: TimestampSource::Creation,
+                    cutoff_anchor: CutoffAnchor::Pool,
+                    creation_tolerance: 120,
+                    strict_excludes: true,
+                    label_expiry: true,
+                    protect_property: "com.example:keep".to_string(),
+                    expires_property: "com.example:expires".to_string(),
+                },
             );
             assert_eq!(config.pool(), "tank");
             assert_eq!(
@@ -326,13 +2081,67 @@ mod tests {
                 &Local.datetime_from_str(date, SNAPSHOT_FORMAT).unwrap()
             );
             assert_eq!(config.exclude_file(), "some-file");
-            assert_eq!(config.should_show_queued(), true);
-            assert_eq!(config.should_show_excluded(), true);
-            assert_eq!(config.should_dry_run(), true);
+            assert!(config.should_show_queued());
+            assert_eq!(config.config_file(), "honeydew.conf");
+            assert_eq!(config.emit_script(), "out.sh");
+            let max_ages: Vec<String> = config.max_ages().iter().map(|m| m.to_string()).collect();
+            assert_eq!(
+                max_ages,
+                vec!["HOURLY=2d", "daily=7d", "DAILY=30d", "MONTHLY=1y"]
+            );
+            assert_eq!(config.max_age_for("DAILY").unwrap().lifetime(), "7d");
+            assert_eq!(config.include_datasets(), &vec!["tank/*".to_string()]);
+            assert_eq!(config.keep_newest(), 3);
+            assert_eq!(config.max_delete_percentage(), Some(25.0));
+            assert_eq!(config.max_delete_count(), Some(1000));
+            assert!(config.allow_mass_deletion());
+            assert!(!config.quarantine());
+            assert!(config.use_ranges());
+            assert!(!config.recursive_sets());
+            assert_eq!(config.max_argument_length(), 4096);
+            assert_eq!(config.jobs(), 4);
+            assert_eq!(config.deletion_order(), &DeletionOrder::Dataset);
+            assert_eq!(config.max_runtime(), "30m");
+            assert_eq!(config.max_deletions(), Some(500));
+            let destroy_config = config.destroy_config();
+            assert_eq!(destroy_config.iteration_count(), 59);
+            assert_eq!(destroy_config.max_argument_length(), 4096);
+            assert_eq!(destroy_config.jobs(), 4);
+            assert_eq!(destroy_config.deletion_order(), &DeletionOrder::Dataset);
+            assert_eq!(
+                destroy_config.max_runtime(),
+                Some(std::time::Duration::from_secs(1800))
+            );
+            assert_eq!(destroy_config.max_deletions(), Some(500));
+            assert_eq!(config.backend(), &Backend::Batched);
+            assert_eq!(config.instruction_limit(), Some(20_000_000));
+            assert_eq!(config.memory_limit(), Some(100_000_000));
+            assert_eq!(
+                config.exclude_datasets(),
+                &vec!["tank/poudriere/*".to_string(), "*/docker/*".to_string()]
+            );
+            assert_eq!(
+                config.cutoff_date_for("WEEKLY"),
+                &Local.datetime_from_str(date, SNAPSHOT_FORMAT).unwrap()
+            );
+            assert!(config.should_show_excluded());
+            assert!(config.should_dry_run());
             assert_eq!(config.iteration_count(), 59);
-            assert_eq!(config.no_confirm(), true);
-            assert_eq!(config.label(), "ANIMALS");
-            assert_eq!(config.should_show_config(), true);
+            assert!(config.no_confirm());
+            assert_eq!(
+                config.labels(),
+                &vec!["ANIMALS".to_string(), "pre*".to_string()]
+            );
+            assert_eq!(config.excluded_labels(), &vec!["MONTHLY".to_string()]);
+            assert!(config.ignore_case());
+            assert!(config.should_show_config());
+            assert_eq!(config.timestamp_source(), &TimestampSource::Creation);
+            assert_eq!(config.cutoff_anchor(), &CutoffAnchor::Pool);
+            assert_eq!(config.creation_tolerance(), 120);
+            assert!(config.strict_excludes());
+            assert!(config.label_expiry());
+            assert_eq!(config.protect_property(), "com.example:keep");
+            assert_eq!(config.expires_property(), "com.example:expires");
         }
         #[test]
         #[should_panic]
@@ -340,16 +2149,12 @@ mod tests {
             let communicator = FakeCommunicator::new(false);
             Config::new(
                 &communicator,
-                "tank",
-                "2099-01-01-0000-00",
-                "some-file",
-                true,
-                true,
-                true,
-                59,
-                true,
-                "ANIMALS",
-                true,
+                Options {
+                    pool: "tank".to_string(),
+                    date: "2099-01-01-0000-00".to_string(),
+                    exclude_file: "some-file".to_string(),
+                    ..Default::default()
+                },
             );
         }
     }
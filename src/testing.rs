@@ -25,30 +25,161 @@
 
 pub mod utility {
     use super::super::*;
+    use std::sync::Mutex;
     pub struct FakeCommunicator {
         does_file_exist: bool,
+        created_snapshots: Mutex<Vec<String>>,
+        created_recursively: Mutex<bool>,
+        written_exclude_file: Mutex<String>,
+        written_plan: Mutex<String>,
+        renamed_snapshots: Mutex<Vec<String>>,
+        written_script: Mutex<String>,
+        channel_programs: Mutex<Vec<String>>,
+        destroyed_snapshots: Mutex<Vec<String>>,
+        destroyed_recursively: Mutex<Vec<String>>,
+        changed_properties: Mutex<Vec<String>>,
     }
     impl FakeCommunicator {
         pub fn new(does_file_exist: bool) -> FakeCommunicator {
-            FakeCommunicator { does_file_exist }
+            FakeCommunicator {
+                does_file_exist,
+                created_snapshots: Mutex::new(Vec::new()),
+                created_recursively: Mutex::new(false),
+                written_exclude_file: Mutex::new(String::new()),
+                written_plan: Mutex::new(String::new()),
+                renamed_snapshots: Mutex::new(Vec::new()),
+                written_script: Mutex::new(String::new()),
+                channel_programs: Mutex::new(Vec::new()),
+                destroyed_snapshots: Mutex::new(Vec::new()),
+                destroyed_recursively: Mutex::new(Vec::new()),
+                changed_properties: Mutex::new(Vec::new()),
+            }
+        }
+        pub fn created_snapshots(&self) -> Vec<String> {
+            self.created_snapshots.lock().unwrap().clone()
+        }
+        pub fn created_recursively(&self) -> bool {
+            *self.created_recursively.lock().unwrap()
+        }
+        pub fn written_exclude_file(&self) -> String {
+            self.written_exclude_file.lock().unwrap().clone()
+        }
+        pub fn written_plan(&self) -> String {
+            self.written_plan.lock().unwrap().clone()
+        }
+        pub fn renamed_snapshots(&self) -> Vec<String> {
+            self.renamed_snapshots.lock().unwrap().clone()
+        }
+        pub fn written_script(&self) -> String {
+            self.written_script.lock().unwrap().clone()
+        }
+        pub fn channel_programs(&self) -> Vec<String> {
+            self.channel_programs.lock().unwrap().clone()
+        }
+        pub fn destroyed_snapshots(&self) -> Vec<String> {
+            self.destroyed_snapshots.lock().unwrap().clone()
+        }
+        pub fn destroyed_recursively(&self) -> Vec<String> {
+            self.destroyed_recursively.lock().unwrap().clone()
+        }
+        pub fn changed_properties(&self) -> Vec<String> {
+            self.changed_properties.lock().unwrap().clone()
         }
     }
     impl Communicator for FakeCommunicator {
-        fn get_snapshots(&self) -> SystemResult {
+        fn get_snapshots(&self, _properties: &[&str]) -> SystemResult {
             Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
                 backup/tank/gentoo/home@2020-07-13-2354-09-CHECKPOINT\n\
                 tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n\
                 tank/gentoo/os@2020-08-13-2354-09-CHECKPOINT\n"
                 .to_string())
         }
+        fn get_snapshot_order(&self) -> SystemResult {
+            Ok("tank/home@2020-09-05-1300-00-CHECKPOINT\n\
+                tank/home@2020-09-05-1310-00-CHECKPOINT\n\
+                tank/home@manual\n\
+                tank/home@2020-09-05-1320-00-CHECKPOINT\n\
+                tank/home@2020-09-05-1330-00-CHECKPOINT\n\
+                tank/home@2020-09-05-1340-00-CHECKPOINT\n"
+                .to_string())
+        }
         fn destroy_snapshots(&self, snapshots: String) -> SystemResult {
+            self.destroyed_snapshots
+                .lock()
+                .unwrap()
+                .push(snapshots.clone());
             Ok(snapshots)
         }
+        fn run_channel_program(
+            &self,
+            pool: &str,
+            program: &str,
+            _instruction_limit: Option<u64>,
+            _memory_limit: Option<u64>,
+        ) -> SystemResult {
+            self.channel_programs
+                .lock()
+                .unwrap()
+                .push(program.to_string());
+            Ok(pool.to_string())
+        }
+        fn rename_snapshot(&self, snapshot: &str, new_name: &str) -> SystemResult {
+            let rename = format!("{} {}", snapshot, new_name);
+            self.renamed_snapshots.lock().unwrap().push(rename.clone());
+            Ok(rename)
+        }
+        fn destroy_snapshot_recursively(&self, snapshot: &str) -> SystemResult {
+            self.destroyed_recursively
+                .lock()
+                .unwrap()
+                .push(snapshot.to_string());
+            Ok(snapshot.to_string())
+        }
+        fn create_snapshots(&self, snapshots: &[String], recursive: bool) -> SystemResult {
+            self.created_snapshots
+                .lock()
+                .unwrap()
+                .extend(snapshots.iter().cloned());
+            *self.created_recursively.lock().unwrap() = recursive;
+            Ok(snapshots.join(" "))
+        }
         fn get_excluded_snapshots(&self, _exclude_file: &str) -> SystemResult {
             Ok("boot@2020-08-12-1237-49-CHECKPOINT\n\
             tank/gentoo/os@2020-07-13-2354-09-CHECKPOINT\n"
                 .to_string())
         }
+        fn get_config_file(&self, _config_file: &str) -> SystemResult {
+            Ok("# Honeydew\n\
+            max-age DAILY=30d\n\
+            max-age MONTHLY=1y\n\
+            exclude-dataset */docker/*\n"
+                .to_string())
+        }
+        fn write_plan(&self, _plan_file: &str, contents: &str) -> SystemResult {
+            *self.written_plan.lock().unwrap() = contents.to_string();
+            Ok(contents.to_string())
+        }
+        fn write_script(&self, _script_file: &str, contents: &str) -> SystemResult {
+            *self.written_script.lock().unwrap() = contents.to_string();
+            Ok(contents.to_string())
+        }
+        fn write_excluded_snapshots(&self, _exclude_file: &str, contents: &str) -> SystemResult {
+            *self.written_exclude_file.lock().unwrap() = contents.to_string();
+            Ok(contents.to_string())
+        }
+        fn set_property(&self, snapshot: &str, property: &str, value: &str) -> SystemResult {
+            let change = format!("set {}={} {}", property, value, snapshot);
+            self.changed_properties.lock().unwrap().push(change.clone());
+            Ok(change)
+        }
+        fn inherit_property(&self, snapshot: &str, property: &str) -> SystemResult {
+            let change = format!("inherit {} {}", property, snapshot);
+            self.changed_properties.lock().unwrap().push(change.clone());
+            Ok(change)
+        }
+        fn get_arg_max(&self) -> SystemResult {
+            Ok("2097152\n".to_string())
+        }
         fn does_file_exist(&self, _filename: &str) -> bool {
             self.does_file_exist
         }
@@ -57,19 +188,57 @@ pub mod utility {
     pub fn get_fake_config(pool: &str, date: &str, label: &str) -> Config {
         Config::new(
             &FakeCommunicator::new(true),
-            pool,
-            date,
-            "",
-            false,
-            false,
+            Options {
+                pool: pool.to_string(),
+                date: date.to_string(),
+                no_confirm: true,
+                label: label.to_string(),
+                ..Default::default()
+            },
+        )
+    }
+
+    pub fn get_fake_pin_config(snapshot: &str, exclude_file: &str, pin: Pin) -> PinConfig {
+        PinConfig::new("", snapshot, exclude_file, pin, DEFAULT_PROTECT_PROPERTY)
+    }
+
+    pub fn get_fake_now() -> DateTime<Local> {
+        Local.ymd(2020, 8, 23).and_hms(10, 23, 17)
+    }
+
+    pub fn get_fake_today() -> NaiveDate {
+        get_fake_now().naive_local().date()
+    }
+
+    pub fn get_fake_apply_config(max_plan_age: &str) -> ApplyConfig {
+        ApplyConfig::new(
+            &FakeCommunicator::new(true),
+            "plan.json",
             false,
-            100,
+            DEFAULT_ITERATIONS,
             true,
-            label,
-            false,
+            max_plan_age,
         )
     }
 
+    pub fn get_fake_destroy_config(max_deletions: Option<usize>) -> DestroyConfig {
+        DestroyConfig::new(
+            DEFAULT_ITERATIONS,
+            DEFAULT_ARG_MAX,
+            DEFAULT_JOBS,
+            DeletionOrder::Oldest,
+            None,
+            max_deletions,
+        )
+    }
+
+    pub fn create_exclusions(lines: &[&str]) -> Vec<Exclusion> {
+        lines
+            .iter()
+            .filter_map(|line| parse_exclusion(line).unwrap())
+            .collect()
+    }
+
     pub fn create_snapshot(dataset: &str, time: &str, label: &str) -> Snapshot {
         let splinters: Vec<_> = dataset.split("/").collect();
         let pool = splinters[0];
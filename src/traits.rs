@@ -25,16 +25,61 @@
 
 use super::enums::SystemResult;
 
-pub trait Communicator {
-    fn get_snapshots(&self) -> SystemResult {
+pub trait Communicator: Sync {
+    fn get_snapshots(&self, _properties: &[&str]) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn get_snapshot_order(&self) -> SystemResult {
         panic!("Not Implemented");
     }
     fn destroy_snapshots(&self, _snapshots: String) -> SystemResult {
         panic!("Not Implemented");
     }
+    fn run_channel_program(
+        &self,
+        _pool: &str,
+        _program: &str,
+        _instruction_limit: Option<u64>,
+        _memory_limit: Option<u64>,
+    ) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn rename_snapshot(&self, _snapshot: &str, _new_name: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn destroy_snapshot_recursively(&self, _snapshot: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn create_snapshots(&self, _snapshots: &[String], _recursive: bool) -> SystemResult {
+        panic!("Not Implemented");
+    }
     fn get_excluded_snapshots(&self, _exclude_file: &str) -> SystemResult {
         panic!("Not Implemented");
     }
+    fn get_config_file(&self, _config_file: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn get_plan(&self, _plan_file: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn write_plan(&self, _plan_file: &str, _contents: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn write_script(&self, _script_file: &str, _contents: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn write_excluded_snapshots(&self, _exclude_file: &str, _contents: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn set_property(&self, _snapshot: &str, _property: &str, _value: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn inherit_property(&self, _snapshot: &str, _property: &str) -> SystemResult {
+        panic!("Not Implemented");
+    }
+    fn get_arg_max(&self) -> SystemResult {
+        panic!("Not Implemented");
+    }
     fn does_file_exist(&self, _filename: &str) -> bool {
         panic!("Not Implemented");
     }